@@ -0,0 +1,65 @@
+use crate::record::Record;
+
+/// `WriteBatch` collects sets and removals that `KvStore::write` applies as one
+/// unit: the batch is logged as a single record, so after a crash either all of
+/// it or none of it is replayed.
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine, WriteBatch};
+/// let store = KvStore::open("/tmp/store")?;
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set("user:1".to_owned(), "alice".to_owned())
+///     .remove("session:1".to_owned());
+/// store.write(batch)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) records: Vec<Record>,
+}
+
+impl WriteBatch {
+    /// An empty batch
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Store a value under the given key, later operations on the same key in
+    /// the batch win
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.records.push(Record::Set {
+            key,
+            value,
+            expires_at: None,
+            seq: 0,
+        });
+        self
+    }
+
+    /// Remove a key, unlike `KvsEngine::remove` a key that does not exist is not
+    /// an error so the batch never fails halfway
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> &mut Self {
+        self.records.push(Record::Rm { key, seq: 0 });
+        self
+    }
+
+    /// Like `set_bytes`
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Like `remove_bytes`
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
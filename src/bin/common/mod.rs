@@ -0,0 +1,61 @@
+//! Helpers shared by the `kvs` and `kvs-client` binaries
+use clap::{Arg, ArgMatches};
+
+/// How keys and values are written on the command line, and how values are
+/// printed back
+#[derive(Clone, Copy)]
+pub enum Encoding {
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl Encoding {
+    /// The `--hex` and `--base64` flags picking the encoding
+    pub fn args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+        [
+            Arg::with_name("hex")
+                .long("hex")
+                .help("Keys and values are hex encoded")
+                .conflicts_with("base64"),
+            Arg::with_name("base64")
+                .long("base64")
+                .help("Keys and values are base64 encoded"),
+        ]
+    }
+
+    pub fn from_matches(matches: &ArgMatches) -> Encoding {
+        if matches.is_present("hex") {
+            Encoding::Hex
+        } else if matches.is_present("base64") {
+            Encoding::Base64
+        } else {
+            Encoding::Utf8
+        }
+    }
+
+    /// Decode the argument `name` if it is present, exiting with an error when
+    /// it is not valid in this encoding
+    pub fn value_of(self, matches: &ArgMatches, name: &str) -> Option<Vec<u8>> {
+        let arg = matches.value_of(name)?;
+        let decoded = match self {
+            Encoding::Utf8 => Ok(arg.as_bytes().to_vec()),
+            Encoding::Hex => hex::decode(arg).map_err(|e| e.to_string()),
+            Encoding::Base64 => base64::decode(arg).map_err(|e| e.to_string()),
+        };
+        let decoded = decoded.unwrap_or_else(|e| {
+            eprintln!("invalid {}: {}", name, e);
+            std::process::exit(1)
+        });
+        Some(decoded)
+    }
+
+    /// Format a value for printing, invalid UTF-8 is replaced in `Utf8` mode
+    pub fn format(self, value: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(value).into_owned(),
+            Encoding::Hex => hex::encode(value),
+            Encoding::Base64 => base64::encode(value),
+        }
+    }
+}
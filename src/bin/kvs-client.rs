@@ -0,0 +1,76 @@
+use clap::{App, Arg, ArgMatches};
+use kvs::{ErrorKind, KvsClient, KvsError, Result};
+
+mod common;
+use common::Encoding;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() -> Result<()> {
+    let addr = Arg::with_name("addr")
+        .long("addr")
+        .value_name("IP-PORT")
+        .default_value(DEFAULT_ADDR);
+    let matches = App::new("kvs-client")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Talk to a kvs-server")
+        .subcommand(
+            App::new("get")
+                .about("Get the string value of given key")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(addr.clone())
+                .args(&Encoding::args()),
+        )
+        .subcommand(
+            App::new("set")
+                .about("Set a key to a value")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(Arg::with_name("VALUE").required(true))
+                .arg(addr.clone())
+                .args(&Encoding::args()),
+        )
+        .subcommand(
+            App::new("rm")
+                .about("Remove a given key")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(addr)
+                .args(&Encoding::args()),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        (name, Some(matches)) => run(name, matches),
+        _ => std::process::exit(1),
+    }
+}
+
+fn run(cmd: &str, matches: &ArgMatches) -> Result<()> {
+    let enc = Encoding::from_matches(matches);
+    let key = enc.value_of(matches, "KEY").unwrap();
+    let mut client = KvsClient::connect(matches.value_of("addr").unwrap())?;
+    match cmd {
+        "get" => {
+            if let Some(v) = client.get_bytes(key)? {
+                println!("{}", enc.format(&v));
+            } else {
+                println!("Key not found")
+            }
+        }
+        "set" => {
+            let val = enc.value_of(matches, "VALUE").unwrap();
+            client.set_bytes(key, val)?;
+        }
+        "rm" => match client.remove_bytes(key) {
+            Ok(()) => {}
+            Err(KvsError::Store(ErrorKind::NotFound)) => {
+                println!("Key not found");
+                std::process::exit(1)
+            }
+            Err(e) => return Err(e),
+        },
+        _ => std::process::exit(1),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use clap::{App, Arg};
+use kvs::{KvsEngine, KvsServer, OpenOptions, Result, SyncPolicy};
+use log::{error, info};
+use std::net::SocketAddr;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let default_sync = SyncPolicy::default().to_string();
+    let matches = App::new("kvs-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Serve a kvs store over TCP")
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP-PORT")
+                .default_value(DEFAULT_ADDR)
+                .validator(|v| {
+                    v.parse::<SocketAddr>()
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                }),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .possible_values(&["kvs"])
+                .default_value("kvs"),
+        )
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .value_name("POLICY")
+                .help("When writes are fsynced: always, never, every=<writes> or interval=<millis>")
+                .default_value(&default_sync)
+                .validator(|v| v.parse::<SyncPolicy>().map(|_| ())),
+        )
+        .get_matches();
+
+    let addr: SocketAddr = matches.value_of("addr").unwrap().parse().unwrap();
+    let engine = matches.value_of("engine").unwrap();
+    let sync: SyncPolicy = matches.value_of("sync").unwrap().parse().unwrap();
+    if let Err(e) = run(addr, engine, sync) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(addr: SocketAddr, engine: &str, sync: SyncPolicy) -> Result<()> {
+    info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    info!("storage engine: {}", engine);
+    info!("sync policy: {}", sync);
+    info!("listening on {}", addr);
+
+    let current_dir = std::env::current_dir()?;
+    match engine {
+        "kvs" => serve(
+            OpenOptions::new().sync_policy(sync).open(current_dir)?,
+            addr,
+        ),
+        _ => unreachable!("engine is validated by clap"),
+    }
+}
+
+fn serve<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
+    KvsServer::new(engine).run(addr)
+}
@@ -1,11 +1,41 @@
-use clap::{App, Arg};
-use kvs::Result;
+use clap::{App, Arg, ArgMatches};
+use kvs::{ErrorKind, KvStore, KvsEngine, KvsError, OpenOptions, Result, SyncPolicy};
+use std::time::Duration;
+
+mod common;
+use common::Encoding;
+
 //TODO: use structopt
 fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let default_sync = SyncPolicy::default().to_string();
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .value_name("POLICY")
+                .help("When writes are fsynced: always, never, every=<writes> or interval=<millis>")
+                .default_value(&default_sync)
+                .validator(|v| v.parse::<SyncPolicy>().map(|_| ()))
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("cf")
+                .long("cf")
+                .value_name("NAME")
+                .help("Column family to use instead of the default key space")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("read-only")
+                .long("read-only")
+                .help("Open the store without writing to it, even while it is in use")
+                .global(true),
+        )
+        .args(&Encoding::args().map(|arg| arg.global(true)))
         .subcommand(
             App::new("get")
                 .about("Get the string value of given key")
@@ -15,47 +45,195 @@ fn main() -> Result<()> {
             App::new("set")
                 .about("Set a key to a value")
                 .arg(Arg::with_name("KEY").required(true))
-                .arg(Arg::with_name("VALUE").required(true)),
+                .arg(Arg::with_name("VALUE").required(true))
+                .arg(
+                    Arg::with_name("if-absent")
+                        .long("if-absent")
+                        .help("Only set the key if it does not exist yet"),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .value_name("DURATION")
+                        .help("Expire the key after DURATION, e.g. 500ms, 30s, 5m, 2h or 1d")
+                        .validator(|v| parse_ttl(&v).map(|_| ()))
+                        .conflicts_with("if-absent"),
+                ),
         )
         .subcommand(
             App::new("rm")
                 .about("Remove a given key")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(
+                    Arg::with_name("if-equals")
+                        .long("if-equals")
+                        .value_name("VALUE")
+                        .help("Only remove the key if it holds VALUE"),
+                ),
+        )
+        .subcommand(
+            App::new("cas")
+                .about("Swap the value of a key if it holds the expected one")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(
+                    Arg::with_name("expected")
+                        .long("expected")
+                        .value_name("VALUE")
+                        .help("Value the key must hold, it must not exist when omitted"),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .long("new")
+                        .value_name("VALUE")
+                        .help("Value to store, the key is removed when omitted"),
+                ),
+        )
+        .subcommand(
+            App::new("ttl")
+                .about("Show the time left before a key expires")
                 .arg(Arg::with_name("KEY").required(true)),
         )
+        .subcommand(
+            App::new("scan")
+                .about("List keys and their values in key order")
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .help("Only list the keys starting with PREFIX"),
+                ),
+        )
+        .subcommand(App::new("status").about("Show the options the store is opened with"))
         .get_matches();
 
-    match matches.subcommand() {
-        ("get", Some(matches)) => {
-            let key = matches.value_of("KEY").unwrap();
-            let current_dir = std::env::current_dir()?;
-            let mut store = kvs::KvStore::open(current_dir)?;
-            if let Some(v) = store.get(key.to_string())? {
-                println!("{}", v);
-            } else {
-                println!("{}", "Key not found")
+    let (cmd, sub) = match matches.subcommand() {
+        (cmd, Some(sub)) => (cmd, sub),
+        _ => std::process::exit(1),
+    };
+    let sync: SyncPolicy = sub.value_of("sync").unwrap().parse().unwrap();
+    let current_dir = std::env::current_dir()?;
+    let store = OpenOptions::new()
+        .sync_policy(sync)
+        .read_only(sub.is_present("read-only"))
+        .open(current_dir)?;
+    let store = match sub.value_of("cf") {
+        Some(name) => store.column_family(name)?,
+        None => store,
+    };
+    match cmd {
+        "status" => {
+            println!("sync policy: {}", store.sync_policy());
+            println!("compaction policy: {}", store.compaction_policy());
+            println!("stale bytes: {}", store.stale_bytes());
+            Ok(())
+        }
+        "scan" => {
+            let enc = Encoding::from_matches(sub);
+            let prefix = enc.value_of(sub, "prefix").unwrap_or_default();
+            for pair in store.scan_prefix(&prefix) {
+                let (key, value) = pair?;
+                println!("{}\t{}", enc.format(&key), enc.format(&value));
             }
+            Ok(())
+        }
+        "ttl" => run_ttl(cmd, sub, &store),
+        "set" if sub.is_present("ttl") => run_ttl(cmd, sub, &store),
+        "cas" => run_conditional(cmd, sub, &store),
+        "set" if sub.is_present("if-absent") => run_conditional(cmd, sub, &store),
+        "rm" if sub.is_present("if-equals") => run_conditional(cmd, sub, &store),
+        _ => run(cmd, sub, store),
+    }
+}
+
+/// Run a conditional write, exiting with a non-zero code when the condition
+/// does not hold
+fn run_conditional(cmd: &str, matches: &ArgMatches, store: &KvStore) -> Result<()> {
+    let enc = Encoding::from_matches(matches);
+    let key = enc.value_of(matches, "KEY").unwrap();
+    let done = match cmd {
+        "cas" => {
+            let expected = enc.value_of(matches, "expected");
+            let new = enc.value_of(matches, "new");
+            store.compare_and_swap(&key, expected.as_deref(), new)?
         }
-        ("set", Some(matches)) => {
-            let key = matches.value_of("KEY").unwrap();
-            let val = matches.value_of("VALUE").unwrap();
-            let current_dir = std::env::current_dir()?;
-            let mut store = kvs::KvStore::open(current_dir)?;
-            store.set(key.to_string(), val.to_string())?;
+        "set" => store.set_if_absent(key, enc.value_of(matches, "VALUE").unwrap())?,
+        "rm" => store.remove_if_equals(&key, &enc.value_of(matches, "if-equals").unwrap())?,
+        _ => std::process::exit(1),
+    };
+    if !done {
+        println!("Condition not met");
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Set a key with an expiry or show the time left before a key expires
+fn run_ttl(cmd: &str, matches: &ArgMatches, store: &KvStore) -> Result<()> {
+    let enc = Encoding::from_matches(matches);
+    let key = enc.value_of(matches, "KEY").unwrap();
+    match cmd {
+        "set" => {
+            let val = enc.value_of(matches, "VALUE").unwrap();
+            let ttl = parse_ttl(matches.value_of("ttl").unwrap()).unwrap();
+            store.set_with_ttl(key, val, ttl)?;
         }
-        ("rm", Some(matches)) => {
-            let key = matches.value_of("KEY").unwrap();
-            let current_dir = std::env::current_dir()?;
-            let mut store = kvs::KvStore::open(current_dir)?;
-            match store.remove(key.to_string()) {
-                Ok(()) => {}
-                Err(kvs::KvsError::Store(kvs::ErrorKind::NotFound)) => {
-                    println!("{}", "Key not found");
-                    std::process::exit(1)
-                }
+        "ttl" => match store.ttl(&key) {
+            // rounded up so a key is never reported as having no time left
+            Ok(Some(ttl)) => println!("{}s", ttl.as_millis().div_ceil(1000)),
+            Ok(None) => println!("No expiry"),
+            Err(KvsError::Store(ErrorKind::NotFound)) => println!("Key not found"),
+            Err(e) => return Err(e),
+        },
+        _ => std::process::exit(1),
+    }
+    Ok(())
+}
+
+/// Parse a duration made of a number and a unit (`ms`, `s`, `m`, `h` or `d`),
+/// a bare number is in seconds
+fn parse_ttl(s: &str) -> std::result::Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().map_err(|_| format!("invalid duration {}", s))?;
+    let millis = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(format!("invalid duration unit {}", unit)),
+    };
+    let millis = n
+        .checked_mul(millis)
+        .ok_or_else(|| format!("duration {} is too long", s))?;
+    Ok(Duration::from_millis(millis))
+}
 
-                Err(e) => return Err(e),
+/// Run a subcommand against any engine
+fn run<E: KvsEngine>(cmd: &str, matches: &ArgMatches, store: E) -> Result<()> {
+    let enc = Encoding::from_matches(matches);
+    let key = enc.value_of(matches, "KEY").unwrap();
+    match cmd {
+        "get" => {
+            if let Some(v) = store.get_bytes(&key)? {
+                println!("{}", enc.format(&v));
+            } else {
+                println!("Key not found")
             }
         }
+        "set" => {
+            let val = enc.value_of(matches, "VALUE").unwrap();
+            store.set_bytes(key, val)?;
+        }
+        "rm" => match store.remove_bytes(&key) {
+            Ok(()) => {}
+            Err(kvs::KvsError::Store(kvs::ErrorKind::NotFound)) => {
+                println!("Key not found");
+                std::process::exit(1)
+            }
+
+            Err(e) => return Err(e),
+        },
         _ => {
             std::process::exit(1);
         }
@@ -0,0 +1,76 @@
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::protocol::{read_frame, write_frame, Request, Response, PROTOCOL_VERSION};
+use crate::{KvsError, Result};
+
+/// `KvsClient` talks to a `kvs-server`, it keeps a single connection open and
+/// mirrors the `KvsEngine` operations.
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// Connect to the server listening on `addr` and agree on the protocol
+    /// version, fails with `ErrorKind::UnsupportedVersion` when the server does
+    /// not speak ours
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let tcp = TcpStream::connect(addr)?;
+        let reader = tcp.try_clone()?;
+        let mut client = KvsClient {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(tcp),
+        };
+        client.request(Request::Handshake {
+            version: PROTOCOL_VERSION,
+        })?;
+        Ok(client)
+    }
+
+    /// Retrieve the value of a key from the server
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.request(Request::Get { key })
+    }
+
+    /// Store a value under the given key on the server
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.request(Request::Set { key, value }).map(|_| ())
+    }
+
+    /// Remove a key on the server, `ErrorKind::NotFound` is returned when the key
+    /// does not exist
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.request(Request::Rm { key }).map(|_| ())
+    }
+
+    /// Like `get_bytes`, fails with `KvsError::Utf8` when the value is not
+    /// valid UTF-8
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `set_bytes`
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Like `remove_bytes`
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    fn request(&mut self, req: Request) -> Result<Option<Vec<u8>>> {
+        write_frame(&mut self.writer, &req)?;
+        match read_frame(&mut self.reader)? {
+            Some(Response::Ok(value)) => Ok(value),
+            Some(Response::Handshake { .. }) => Ok(None),
+            Some(Response::Store(kind)) => Err(KvsError::Store(kind)),
+            Some(Response::Err(msg)) => Err(KvsError::Remote(msg)),
+            None => Err(KvsError::Remote("server closed the connection".to_owned())),
+        }
+    }
+}
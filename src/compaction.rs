@@ -0,0 +1,203 @@
+//! Background compaction: a worker thread copies the live records of the sealed
+//! log files into a new one while the store keeps serving reads and writes
+//! from the current generation. The worker then swaps the index entries and
+//! readers over itself, so no handle has to be around to collect the result.
+//!
+//! A new generation is written to a temporary file that only becomes a log
+//! once it is synced and renamed into place, the sealed logs are deleted after
+//! that. A crash at any point leaves either the old generation (plus a
+//! temporary file `open` cleans up) or both generations, which replay in the
+//! right order since the new one has a higher id.
+use log::{error, warn};
+use std::collections::{hash_map::Entry, HashMap};
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::hint;
+use crate::kv::{
+    hint_path, log_path, now_millis, read_exact_at, remove_if_exists, sync_dir, tmp_path,
+    BufPosWriter, CmdPos, LiveKey, Shared,
+};
+use crate::Result;
+
+/// Everything the worker needs to write a new generation
+pub(crate) struct Job {
+    pub dir: PathBuf,
+    /// Id of the log file the live records are copied into
+    pub out_id: usize,
+    /// Log files being compacted, none of them is written to anymore
+    pub sealed: Vec<usize>,
+    /// Live entries as of the start of the compaction
+    pub entries: Vec<LiveKey>,
+    /// Sequence number of the store when the compaction started
+    pub seq: u64,
+}
+
+/// Outcome of a `Job`
+pub(crate) struct Compacted {
+    pub out_id: usize,
+    pub sealed: Vec<usize>,
+    /// For every copied key, its position before the copy and the key as
+    /// found in the new log. Keys that expired are not copied.
+    pub moved: Vec<(CmdPos, LiveKey)>,
+    /// Size of the new log file
+    pub sz: usize,
+    /// Versions of the keys older than the ones live at this sequence number
+    /// are gone once the compaction is done
+    pub seq: u64,
+}
+
+/// Where to report a finished job, if anyone is waiting for it
+type Reply = Option<Sender<Result<()>>>;
+
+/// Handle to the compaction thread, the store makes sure at most one job is in
+/// flight at a time
+pub(crate) struct Compactor {
+    jobs: Mutex<Option<Sender<(Job, Reply)>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    pub fn spawn(shared: Arc<Shared>) -> Result<Self> {
+        let (jobs, job_rx) = mpsc::channel::<(Job, Reply)>();
+        let handle = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || {
+                for (job, reply) in job_rx {
+                    let res = compact(job).and_then(|done| shared.finish_compaction(done));
+                    shared.compaction_finished();
+                    match reply {
+                        Some(reply) => {
+                            let _ = reply.send(res);
+                        }
+                        None => {
+                            if let Err(e) = res {
+                                error!("background compaction failed: {}", e);
+                            }
+                        }
+                    }
+                }
+            })?;
+        Ok(Compactor {
+            jobs: Mutex::new(Some(jobs)),
+            handle: Some(handle),
+        })
+    }
+
+    pub fn submit(&self, job: Job, reply: Reply) {
+        self.jobs
+            .lock()
+            .unwrap()
+            .as_ref()
+            .expect("compaction thread is running")
+            .send((job, reply))
+            .expect("compaction thread exited");
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // closing the channel stops the worker once it is done with its job
+        self.jobs.get_mut().unwrap().take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("compaction thread panicked");
+            }
+        }
+    }
+}
+
+/// Copy the live records of `job` into a temporary file and move it into
+/// place as the new log once it is durable
+fn compact(job: Job) -> Result<Compacted> {
+    let tmp = tmp_path(&job.dir, job.out_id);
+    let done = match copy_live(&job, &tmp) {
+        Ok(done) => done,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+    std::fs::rename(&tmp, log_path(&job.dir, job.out_id))?;
+    // the log is complete without its hint, failing to write one only makes
+    // the next open slower
+    if let Err(e) = write_hint(&job.dir, &done) {
+        warn!("failed to write the hint for log {}: {}", done.out_id, e);
+        let _ = std::fs::remove_file(hint_path(&job.dir, done.out_id));
+    }
+    if let Err(e) = sync_dir(&job.dir) {
+        discard_output(&job.dir, job.out_id);
+        return Err(e);
+    }
+    Ok(done)
+}
+
+/// Delete the output of a job that failed once it was moved into place. No
+/// compaction would ever seal that log, and replaying it on the next open
+/// would bring back keys removed in the meantime.
+pub(crate) fn discard_output(dir: &Path, out_id: usize) {
+    for path in &[hint_path(dir, out_id), log_path(dir, out_id)] {
+        if let Err(e) = remove_if_exists(path) {
+            error!("failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn write_hint(dir: &Path, done: &Compacted) -> Result<()> {
+    let entries = done.moved.iter().map(|(_, live)| live);
+    let mut f = File::create(hint_path(dir, done.out_id))?;
+    f.write_all(&hint::encode(done.sz as u64, done.seq, entries))?;
+    f.sync_all()?;
+    Ok(())
+}
+
+fn copy_live(job: &Job, out_path: &Path) -> Result<Compacted> {
+    let out = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(out_path)?;
+    let mut writer = BufPosWriter::new(out)?;
+    let mut logs: HashMap<usize, File> = HashMap::new();
+    let mut moved = Vec::with_capacity(job.entries.len());
+    let now = now_millis();
+
+    for live in &job.entries {
+        if live.is_expired(now) {
+            continue;
+        }
+        let old = &live.pos;
+        let log = match logs.entry(old.f_id) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(File::open(log_path(&job.dir, old.f_id))?),
+        };
+        let mut buf = vec![0u8; old.sz];
+        read_exact_at(log, &mut buf, old.pos as u64)?;
+        let pos = writer.pos;
+        writer.write_all(&buf)?;
+        let new = CmdPos {
+            f_id: job.out_id,
+            pos,
+            sz: old.sz,
+        };
+        moved.push((
+            *old,
+            LiveKey {
+                pos: new,
+                ..live.clone()
+            },
+        ));
+    }
+    writer.sync()?;
+
+    Ok(Compacted {
+        out_id: job.out_id,
+        sealed: job.sealed.clone(),
+        moved,
+        sz: writer.pos,
+        seq: job.seq,
+    })
+}
@@ -0,0 +1,48 @@
+use std::path::PathBuf;
+
+use crate::Result;
+
+/// `KvsEngine` is the interface every storage backend has to implement so that
+/// the CLI (and anything else built on top of the lib) does not depend on a
+/// concrete store.
+///
+/// Engines are handles: cloning one is cheap and every clone operates on the
+/// same data, so a clone can be moved into each thread that needs the store.
+///
+/// Keys and values are arbitrary bytes, the `String` methods are a convenience
+/// layer on top of the byte ones.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Open (or create) the store that lives in the given directory
+    fn open(path: impl Into<PathBuf>) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Retrieve the value of a key, `None` is returned when the key does not exist
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Store a value under the given key, overwriting any previous value
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Remove a key from the store, fails with `ErrorKind::NotFound` when the
+    /// key does not exist
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+
+    /// Like `get_bytes`, fails with `KvsError::Utf8` when the value is not
+    /// valid UTF-8
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `set_bytes`
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Like `remove_bytes`
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+}
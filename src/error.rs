@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 #[derive(Debug)]
@@ -6,14 +7,43 @@ pub enum KvsError {
     // Errors from ext libs
     Io(std::io::Error),
     Serde(serde_json::Error),
+    // A value read through the `String` API is not valid UTF-8
+    Utf8(std::string::FromUtf8Error),
     // Errors from this lib
     Store(ErrorKind),
+    // Errors reported by a remote kvs-server
+    Remote(String),
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialOrd, PartialEq, Ord)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialOrd, PartialEq, Ord, Serialize, Deserialize)]
 pub enum ErrorKind {
     NotFound,
     UnsupportedCommand,
+    UnsupportedVersion {
+        client: u32,
+        server: u32,
+    },
+    FrameTooLarge,
+    /// A log record failed its integrity checks, `file` is the id of the log
+    /// and `offset` where the record starts
+    Corruption {
+        file: usize,
+        offset: u64,
+    },
+    /// A key read by a transaction was written by someone else before it
+    /// committed
+    TransactionConflict,
+    /// The version asked for is older than what compaction kept
+    VersionCompacted,
+    /// A column family name is empty or has characters other than ASCII
+    /// letters, digits, `-` and `_`
+    InvalidColumnFamily,
+    /// The store is already open, in this process or another one
+    StoreLocked,
+    /// A write was attempted on a store opened read-only
+    ReadOnly,
+    /// The directory has JSON logs written by an older version of the store
+    LegacyLogFormat,
 }
 
 impl ErrorKind {
@@ -21,6 +51,15 @@ impl ErrorKind {
         match *self {
             ErrorKind::NotFound => "Key not found",
             ErrorKind::UnsupportedCommand => "command is not supported",
+            ErrorKind::UnsupportedVersion { .. } => "protocol version is not supported",
+            ErrorKind::FrameTooLarge => "message frame is too large",
+            ErrorKind::Corruption { .. } => "log record is corrupted",
+            ErrorKind::TransactionConflict => "transaction conflicts with a concurrent write",
+            ErrorKind::VersionCompacted => "version was removed by compaction",
+            ErrorKind::InvalidColumnFamily => "column family name is invalid",
+            ErrorKind::StoreLocked => "store is locked by another handle",
+            ErrorKind::ReadOnly => "store is open read-only",
+            ErrorKind::LegacyLogFormat => "store has logs in the old JSON format",
         }
     }
 }
@@ -30,7 +69,9 @@ impl fmt::Display for KvsError {
         match self {
             KvsError::Io(err) => err.fmt(f),
             KvsError::Serde(err) => err.fmt(f),
+            KvsError::Utf8(err) => err.fmt(f),
             KvsError::Store(err) => write!(f, "store error occurred {:?}", err),
+            KvsError::Remote(msg) => write!(f, "server error occurred: {}", msg),
         }
     }
 }
@@ -47,5 +88,11 @@ impl From<serde_json::Error> for KvsError {
         KvsError::Serde(err)
     }
 }
-///
+impl From<std::string::FromUtf8Error> for KvsError {
+    fn from(err: std::string::FromUtf8Error) -> KvsError {
+        KvsError::Utf8(err)
+    }
+}
+
+/// Result type used throughout the lib, errors are always a `KvsError`
 pub type Result<T> = std::result::Result<T, KvsError>;
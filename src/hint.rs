@@ -0,0 +1,89 @@
+//! Hint files let `open` rebuild the index for a compacted log without reading
+//! every record in it. Compaction writes `{id}.hint` next to `{id}.log`:
+//!
+//! ```text
+//! +---------+------------+-------------+---------+---------+---------+-----
+//! | crc u32 | version u8 | log_len u64 | seq u64 | entry 0 | entry 1 | ...
+//! +---------+------------+-------------+---------+---------+---------+-----
+//!
+//! entry: | pos u64 | sz u32 | expires_at u64 | seq u64 | key_len u32 | key |
+//! ```
+//!
+//! with one entry per live key, all of them pointing into the log with the
+//! same id. Integers are little-endian, `crc` is the CRC32 of everything after
+//! it and an `expires_at` of 0 means the key does not expire. The header `seq`
+//! is the sequence number the compaction ran at, older versions of the keys
+//! are gone. A hint whose checksum or version does not match, or whose
+//! `log_len` is not the size of its log, is ignored and the log replayed.
+use crate::kv::{CmdPos, LiveKey};
+use crate::record::{u32_at, u64_at};
+
+const VERSION: u8 = 3;
+const HEADER_SZ: usize = 21;
+const ENTRY_HEADER_SZ: usize = 32;
+
+/// Content of a hint file
+pub struct Hint {
+    /// Sequence number the compaction that wrote the log ran at
+    pub seq: u64,
+    pub entries: Vec<LiveKey>,
+}
+
+/// Encode the hint for a log of `log_len` bytes holding `entries`, written by
+/// a compaction at `seq`
+pub fn encode<'a>(log_len: u64, seq: u64, entries: impl Iterator<Item = &'a LiveKey>) -> Vec<u8> {
+    let mut buf = vec![0u8; 4];
+    buf.push(VERSION);
+    buf.extend_from_slice(&log_len.to_le_bytes());
+    buf.extend_from_slice(&seq.to_le_bytes());
+    for entry in entries {
+        buf.extend_from_slice(&(entry.pos.pos as u64).to_le_bytes());
+        buf.extend_from_slice(&(entry.pos.sz as u32).to_le_bytes());
+        buf.extend_from_slice(&entry.expires_at.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&entry.seq.to_le_bytes());
+        buf.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&entry.key);
+    }
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+/// Decode the hint for log `f_id`, `None` is returned when the hint is damaged
+/// or does not describe a log of `log_len` bytes
+pub fn decode(buf: &[u8], f_id: usize, log_len: u64) -> Option<Hint> {
+    if buf.len() < HEADER_SZ
+        || crc32fast::hash(&buf[4..]) != u32_at(buf, 0)
+        || buf[4] != VERSION
+        || u64_at(buf, 5) != log_len
+    {
+        return None;
+    }
+    let mut entries = Vec::new();
+    let mut rest = &buf[HEADER_SZ..];
+    while !rest.is_empty() {
+        if rest.len() < ENTRY_HEADER_SZ {
+            return None;
+        }
+        let pos = u64_at(rest, 0) as usize;
+        let sz = u32_at(rest, 8) as usize;
+        let expires_at = Some(u64_at(rest, 12)).filter(|&at| at != 0);
+        let seq = u64_at(rest, 20);
+        let key_len = u32_at(rest, 28) as usize;
+        let key = rest.get(ENTRY_HEADER_SZ..ENTRY_HEADER_SZ + key_len)?;
+        if (pos + sz) as u64 > log_len {
+            return None;
+        }
+        entries.push(LiveKey {
+            key: key.to_vec(),
+            pos: CmdPos { f_id, pos, sz },
+            expires_at,
+            seq,
+        });
+        rest = &rest[ENTRY_HEADER_SZ + key_len..];
+    }
+    Some(Hint {
+        seq: u64_at(buf, 13),
+        entries,
+    })
+}
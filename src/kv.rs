@@ -1,294 +1,1250 @@
 //#![deny(missing_docs)]
-use serde::{Deserialize, Serialize};
+use fs2::FileExt;
+use log::warn;
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::{prelude::*, BufReader, BufWriter, Seek, SeekFrom};
-use std::{collections::HashMap, path::Path, path::PathBuf};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::{ErrorKind, KvsError, Result};
+use crate::compaction::{self, Compacted, Compactor, Job};
+use crate::hint::{self, Hint};
+use crate::record::{self, Next, Record};
+use crate::{
+    CompactionPolicy, ErrorKind, KvsEngine, KvsError, OpenOptions, Result, Snapshot, SyncPolicy,
+    Transaction, WriteBatch,
+};
 
-// For now, will pick JSON but as I benchmark I will be thinking
-// of moving to MessagePack
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "command")]
-enum Command {
-    Set { key: String, value: String },
-    Rm { key: String },
-}
-
-const MAX_STORE_SZ: usize = 2048;
+/// Extension of compaction output that is not a log yet
+const TMP_EXT: &str = "compact";
+/// Extension of compacted logs kept around for the snapshots reading them
+const RETIRED_EXT: &str = "retired";
+/// Directory of the column families, inside the directory of the store
+const CF_DIR: &str = "cf";
+/// File locked by the process that has the store open
+const LOCK_FILE: &str = "LOCK";
+/// How many times a read-only open lists the logs when one of them disappears
+/// while it is read
+const READ_ONLY_OPEN_ATTEMPTS: usize = 5;
 
-/// `KvStore` is a simple struct wrapper over a `std::collection::HashMap` to give some abstraction
-/// to the <KV> store.
+/// `KvStore` is a log-structured <KV> store with an in-memory index of where
+/// every key lives on disk.
+///
+/// Cloning is cheap and every clone works on the same store, so a clone can be
+/// handed to each thread. Writes are serialized, reads run concurrently.
+#[derive(Clone)]
 pub struct KvStore {
-    idx: HashMap<String, CmdPos>,
-    writer: BufPosWriter<File>,
-    readers: HashMap<usize, BufPosReader<File>>,
-    active_id: usize,
-    total_sz: usize,
+    shared: Arc<Shared>,
+    compactor: Arc<Compactor>,
+    // stops the sync thread once the last clone is gone
+    _syncer: Arc<Syncer>,
+}
+
+/// State shared by every clone of a store and its compaction thread
+pub(crate) struct Shared {
     path: PathBuf, // Credit to pingcap guide
+    options: OpenOptions,
+    keydir: RwLock<KeyDir>,
+    writer: Mutex<Writer>,
+    // signalled whenever a compaction finishes
+    compaction_done: Condvar,
+    pins: Mutex<Pins>,
+    // column families opened so far, so every handle to one shares its state
+    families: Mutex<HashMap<String, KvStore>>,
+    // the lock on the directory goes away with it, read-only stores do not
+    // take it
+    _lock: Option<File>,
 }
 
-#[derive(Debug)]
-pub struct CmdPos {
-    f_id: usize,
-    pos: usize,
-    sz: usize,
+/// Log files in use by snapshots, taken after the keydir lock when both are
+/// held
+#[derive(Default)]
+struct Pins {
+    // number of snapshots reading from each log
+    counts: HashMap<usize, usize>,
+    // compacted logs waiting for their last snapshot to go away
+    retired: HashSet<usize>,
 }
 
-///
-impl KvStore {
-    /// Create a new instance of KvStore by in turn creating a HashMap
-    fn new(
-        writer: BufPosWriter<File>,
-        readers: HashMap<usize, BufPosReader<File>>,
-        idx: HashMap<String, CmdPos>,
-        active_id: usize,
-        total_sz: usize,
-        path: PathBuf,
-    ) -> Result<Self> {
-        Ok(KvStore {
-            idx,
-            writer,
-            readers,
-            active_id,
-            total_sz,
-            path,
-        })
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct CmdPos {
+    pub f_id: usize,
+    pub pos: usize,
+    pub sz: usize,
+}
+
+/// A version of a key: where it was written, when and whether it is a removal
+#[derive(Clone, Copy, Debug)]
+struct Version {
+    pos: CmdPos,
+    seq: u64,
+    expires_at: Option<u64>,
+    removed: bool,
+}
+
+impl Version {
+    fn is_live(&self, now: u64) -> bool {
+        !self.removed && !expired(self.expires_at, now)
     }
+}
 
-    /// Retrieve a variable from the KvStore and return as an Option<String> depending on whether
-    /// the key exists
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(p) = self.idx.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&p.f_id)
-                .expect("could not get reader associated with key");
-            let mut buf = vec![0u8; p.sz];
-            reader.seek(SeekFrom::Start(p.pos as u64))?;
-            reader.read(&mut buf)?;
-            let cmd: Command = serde_json::from_slice(&buf)?;
-            if let Command::Set { key: _, value } = cmd {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::Store(ErrorKind::UnsupportedCommand))
-            }
-        } else {
-            Ok(None)
-        }
+/// A key of the index along with where its value is, as handed to compaction
+/// and stored in hint files
+#[derive(Clone, Debug)]
+pub(crate) struct LiveKey {
+    pub key: Vec<u8>,
+    pub pos: CmdPos,
+    pub expires_at: Option<u64>,
+    pub seq: u64,
+}
+
+impl LiveKey {
+    pub fn is_expired(&self, now: u64) -> bool {
+        expired(self.expires_at, now)
     }
+}
 
-    /// Store a value inside the KvStore using a key that can be subsequently used to retrieve
-    /// the value
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let log_cmd = Command::Set {
-            key: key.to_owned(),
-            value,
-        };
-        let cmd = serde_json::to_string(&log_cmd)?;
-        let pos = self.writer.pos;
-        let sz = self.writer.write(cmd.as_bytes())?;
-        let pos = CmdPos {
-            f_id: self.active_id,
+/// Size of a log file and how many of its bytes belong to overwritten or
+/// removed keys that compaction would reclaim
+#[derive(Clone, Copy, Debug, Default)]
+struct FileStats {
+    len: u64,
+    stale: u64,
+}
+
+/// The index (bitcask calls it the keydir) along with the log files it points
+/// into
+#[derive(Clone, Default)]
+pub(crate) struct KeyDir {
+    // ordered so ranges of keys can be scanned, the versions of a key are kept
+    // oldest first until compaction drops the ones that are not live anymore
+    entries: BTreeMap<Vec<u8>, Vec<Version>>,
+    // sequence number of the last write applied
+    seq: u64,
+    // versions that were already overwritten or removed at this sequence
+    // number may have been compacted away
+    compacted_seq: u64,
+    // size of the live versions with a TTL by `expiry_key`, they move to the
+    // stale bytes of their file once expired
+    expiring: BTreeMap<(u64, usize, usize), usize>,
+    files: BTreeMap<usize, FileStats>,
+    // read handles are only used for positional reads (or are mappings), so
+    // any number of readers can share them
+    logs: BTreeMap<usize, Arc<LogFile>>,
+}
+
+impl KeyDir {
+    /// Account for a `Set` record appended at `pos`
+    fn record_set(&mut self, key: Vec<u8>, pos: CmdPos, expires_at: Option<u64>, seq: u64) {
+        let version = Version {
             pos,
-            sz,
+            seq,
+            expires_at,
+            removed: false,
         };
-        self.idx.insert(key, pos);
-        self.writer.flush()?;
-        self.total_sz += sz;
+        self.files.entry(pos.f_id).or_default().len += pos.sz as u64;
+        if let Some(old) = self.push_version(key, version) {
+            self.mark_stale(old);
+        }
+    }
 
-        if self.total_sz >= MAX_STORE_SZ {
-            self.compact()
-        } else {
-            Ok(())
+    /// Account for a `Rm` record appended at `pos`
+    fn record_rm(&mut self, key: Vec<u8>, pos: CmdPos, seq: u64) {
+        let version = Version {
+            pos,
+            seq,
+            expires_at: None,
+            removed: true,
+        };
+        self.files.entry(pos.f_id).or_default().len += pos.sz as u64;
+        if let Some(old) = self.push_version(key, version) {
+            self.mark_stale(old);
         }
+        // the removal itself is dead weight as soon as it is written
+        self.mark_stale(pos);
     }
 
-    /// Remove a variable from the KvStore
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.idx.contains_key(&key) {
-            let cmd = serde_json::to_string(&Command::Rm {
-                key: key.to_owned(),
-            })?;
-            let sz = self.writer.write(cmd.as_bytes())?;
-            self.idx.remove(&key);
-            self.writer.flush()?;
-            self.total_sz += sz;
+    /// Make `version` the latest of `key`, returns where the value it replaces
+    /// is if there was one that is not counted as stale yet
+    fn push_version(&mut self, key: Vec<u8>, version: Version) -> Option<CmdPos> {
+        self.seq = self.seq.max(version.seq);
+        if let Some(at) = version.expires_at {
+            self.expiring
+                .insert(expiry_key(at, version.pos), version.pos.sz);
+        }
+        let versions = self.entries.entry(key).or_default();
+        let old = versions.last().filter(|v| !v.removed).copied();
+        versions.push(version);
+        let old = old?;
+        match old.expires_at {
+            // gone from `expiring` once counted as stale by `expire`
+            Some(at) => self
+                .expiring
+                .remove(&expiry_key(at, old.pos))
+                .map(|_| old.pos),
+            None => Some(old.pos),
+        }
+    }
 
-            if self.total_sz >= MAX_STORE_SZ {
-                self.compact()
-            } else {
-                Ok(())
+    /// Count the versions that expired by `now` as stale
+    fn expire(&mut self, now: u64) {
+        while let Some((&(at, f_id, pos), &sz)) = self.expiring.iter().next() {
+            if at > now {
+                break;
             }
-        } else {
-            Err(KvsError::Store(ErrorKind::NotFound))
+            self.expiring.remove(&(at, f_id, pos));
+            self.mark_stale(CmdPos { f_id, pos, sz });
         }
     }
 
-    ///
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
-        std::fs::create_dir_all(&path)?;
-        let mut files = std::fs::read_dir(&path)?
-            .filter_map(std::io::Result::ok)
-            .filter_map(|e| match e.file_name().to_str() {
-                Some(v) => {
-                    if v.contains("log") {
-                        Some(v.to_owned())
-                    } else {
-                        None
-                    }
+    /// Account for any record appended at `pos`, the records wrapped in a
+    /// batch are accounted for one by one
+    fn record(&mut self, record: Record, pos: CmdPos) {
+        // records logged without a sequence number are numbered in log order
+        let seq = match record.seq() {
+            0 => self.seq + 1,
+            seq => seq,
+        };
+        match record {
+            Record::Set {
+                key, expires_at, ..
+            } => self.record_set(key, pos, expires_at, seq),
+            Record::Rm { key, .. } => self.record_rm(key, pos, seq),
+            Record::Batch(records) => {
+                // like a removal, the batch header is dead weight right away
+                let header = CmdPos {
+                    sz: record::BATCH_HEADER_SZ,
+                    ..pos
+                };
+                self.files.entry(pos.f_id).or_default().len += header.sz as u64;
+                self.mark_stale(header);
+                let mut at = pos.pos + header.sz;
+                for record in records {
+                    let sz = record.encoded_len();
+                    self.record(record, CmdPos { pos: at, sz, ..pos });
+                    at += sz;
                 }
-                _ => None,
+            }
+        }
+    }
+
+    /// Account for the record at `old` no longer being live
+    fn mark_stale(&mut self, old: CmdPos) {
+        self.files.entry(old.f_id).or_default().stale += old.sz as u64;
+    }
+
+    /// The latest version of `key` unless it was removed or has expired
+    fn live(&self, key: &[u8]) -> Option<&Version> {
+        self.entries
+            .get(key)?
+            .last()
+            .filter(|v| v.is_live(now_millis()))
+    }
+
+    /// The version of `key` that was the latest at `seq`, unless it was
+    /// removed or has expired. Fails with `ErrorKind::VersionCompacted` when
+    /// compaction dropped the versions that would tell.
+    fn version_at(&self, key: &[u8], seq: u64) -> Result<Option<&Version>> {
+        let versions = self.entries.get(key).map_or(&[][..], Vec::as_slice);
+        match versions.iter().rev().find(|v| v.seq <= seq) {
+            Some(v) => Ok(Some(v).filter(|v| v.is_live(now_millis()))),
+            // any version older than the first one kept was live before
+            // `compacted_seq` at the latest
+            None if seq < self.compacted_seq => Err(KvsError::Store(ErrorKind::VersionCompacted)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether `key` may have been set or removed after `seq`. Keys that are
+    /// gone from the index count as modified by the last compaction.
+    fn modified_since(&self, key: &[u8], seq: u64) -> bool {
+        match self.entries.get(key).and_then(|versions| versions.last()) {
+            Some(v) => v.seq > seq,
+            None => self.compacted_seq > seq,
+        }
+    }
+
+    /// Where the value of `key` is, unless it does not exist or has expired
+    pub(crate) fn locate(&self, key: &[u8]) -> Option<ScanEntry> {
+        self.live(key).map(|v| self.log_of(v))
+    }
+
+    /// Like `locate`, for the version of `key` that was the latest at `seq`
+    fn locate_at(&self, key: &[u8], seq: u64) -> Result<Option<ScanEntry>> {
+        Ok(self.version_at(key, seq)?.map(|v| self.log_of(v)))
+    }
+
+    /// Copy of the index that only has the live version of every key, all a
+    /// snapshot reads, along with the logs they are in
+    pub(crate) fn live_copy(&self) -> KeyDir {
+        let now = now_millis();
+        let entries = self
+            .entries
+            .iter()
+            .filter_map(|(key, versions)| {
+                let v = versions.last().filter(|v| v.is_live(now))?;
+                Some((key.clone(), vec![*v]))
             })
-            .map(|s| {
-                s.find('-')
-                    .map(|i| Some(s[..i].to_string()))
-                    .map(|name| {
-                        name.unwrap()
-                            .parse::<usize>()
-                            .expect("file name not supported")
-                    })
-                    .expect("failed to parse file name")
+            .collect();
+        KeyDir {
+            entries,
+            seq: self.seq,
+            logs: self.logs.clone(),
+            ..KeyDir::default()
+        }
+    }
+
+    fn log_of(&self, version: &Version) -> ScanEntry {
+        (version.pos, self.logs[&version.pos.f_id].clone())
+    }
+
+    /// Scan of the keys in `range` that have not been removed or expired
+    pub(crate) fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan {
+        let now = now_millis();
+        let entries = self
+            .entries
+            .range(range)
+            .filter_map(|(key, versions)| {
+                let v = versions.last().filter(|v| v.is_live(now))?;
+                Some((key.clone(), self.log_of(v)))
             })
-            .collect::<Vec<usize>>();
-
-        files.sort();
-        let mut total_sz = 0usize;
-        let mut readers: HashMap<usize, BufPosReader<File>> = HashMap::new();
-        let mut idx: HashMap<String, CmdPos> = HashMap::new();
-        for f_id in files {
-            let file = File::open(log_path(&path, f_id))?;
-            let mut reader = BufPosReader::new(file)?;
-            total_sz += replay(&mut reader, &mut idx, f_id)?;
-            readers.insert(f_id, reader);
-        }
-
-        let active_id: usize;
-        let active_file: File;
-        if let Some(num) = readers.keys().max() {
-            active_id = *num;
-            active_file = std::fs::OpenOptions::new()
-                .read(true)
-                .append(true)
-                .write(true)
-                .open(log_path(&path, active_id))?;
+            .collect::<Vec<_>>();
+        Scan {
+            entries: entries.into_iter(),
+        }
+    }
+
+    fn stale_bytes(&self) -> u64 {
+        self.files.values().map(|f| f.stale).sum()
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.files.values().map(|f| f.len).sum()
+    }
+}
+
+/// The active log and the bookkeeping of the write path, only ever used with
+/// the writer lock held
+struct Writer {
+    // `None` for a read-only store
+    writer: Option<BufPosWriter<File>>,
+    active_id: usize,
+    sync: SyncPolicy,
+    // writes appended since the last fsync
+    unsynced: usize,
+    last_sync: Instant,
+    compacting: bool,
+}
+
+impl KvStore {
+    /// Open the store found in `path` without writing to it, see
+    /// `OpenOptions::read_only`
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        OpenOptions::new().read_only(true).open(path)
+    }
+
+    /// Open the store found in `path`, rebuilding the index from the hint of
+    /// every compacted log and by replaying the other logs
+    pub(crate) fn open_with(path: PathBuf, options: OpenOptions) -> Result<KvStore> {
+        let lock = if options.read_only {
+            None
         } else {
-            active_id = 0;
-            let file_path = log_path(&path, active_id);
-            active_file = std::fs::OpenOptions::new()
+            std::fs::create_dir_all(&path)?;
+            let lock = lock_dir(&path)?;
+            remove_tmp_files(&path)?;
+            Some(lock)
+        };
+        if has_legacy_logs(&path)? {
+            return Err(KvsError::Store(ErrorKind::LegacyLogFormat));
+        }
+        let mut attempts = 1;
+        let (keydir, last_id) = loop {
+            match load_logs(&path, &options) {
+                // a writer compacting the store deletes the logs it replaced,
+                // their records are in a newer log the next listing finds
+                Err(KvsError::Io(ref e))
+                    if options.read_only
+                        && e.kind() == std::io::ErrorKind::NotFound
+                        && attempts < READ_ONLY_OPEN_ATTEMPTS =>
+                {
+                    attempts += 1;
+                }
+                res => break res?,
+            }
+        };
+        KvStore::start(path, options, keydir, last_id, lock)
+    }
+
+    /// Start a store over the index of the logs in `path`, `last_id` is the
+    /// newest of them
+    fn start(
+        path: PathBuf,
+        options: OpenOptions,
+        mut keydir: KeyDir,
+        last_id: Option<usize>,
+        lock: Option<File>,
+    ) -> Result<KvStore> {
+        keydir.expire(now_millis());
+
+        let active_id = last_id.unwrap_or(0);
+        // a read-only store never writes, not even to create its first log
+        let writer = if options.read_only {
+            None
+        } else {
+            let active_file = std::fs::OpenOptions::new()
                 .read(true)
                 .append(true)
-                .write(true)
                 .create(true)
-                .open(&file_path)?;
+                .open(log_path(&path, active_id))?;
+            keydir.files.entry(active_id).or_default();
+            let active = LogFile::open(&log_path(&path, active_id), false)?;
+            keydir.logs.insert(active_id, Arc::new(active));
+            let mut writer = BufPosWriter::new(active_file)?;
+            writer.seek(SeekFrom::End(0))?;
+            Some(writer)
+        };
+
+        let shared = Arc::new(Shared {
+            path,
+            keydir: RwLock::new(keydir),
+            writer: Mutex::new(Writer {
+                writer,
+                active_id,
+                sync: options.sync,
+                unsynced: 0,
+                last_sync: Instant::now(),
+                compacting: false,
+            }),
+            options,
+            compaction_done: Condvar::new(),
+            pins: Mutex::default(),
+            families: Mutex::default(),
+            _lock: lock,
+        });
+        Ok(KvStore {
+            compactor: Arc::new(Compactor::spawn(shared.clone())?),
+            _syncer: Arc::new(Syncer::spawn(shared.clone())?),
+            shared,
+        })
+    }
+
+    /// The policy deciding when writes are synced to disk
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.shared.options.sync
+    }
+
+    /// The policy deciding when stale log records are compacted away
+    pub fn compaction_policy(&self) -> CompactionPolicy {
+        self.shared.options.compaction
+    }
+
+    /// Bytes held by overwritten or removed keys that compaction would reclaim
+    pub fn stale_bytes(&self) -> u64 {
+        self.shared.keydir.read().unwrap().stale_bytes()
+    }
 
-            let f = File::open(&file_path)?;
-            let f = BufPosReader::new(f)?;
-            readers.insert(0, f);
+    /// Compact the log right away, waiting for a background compaction that is
+    /// already running first. This works regardless of the `CompactionPolicy`.
+    pub fn compact_now(&self) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        while writer.compacting {
+            writer = self.shared.compaction_done.wait(writer).unwrap();
         }
-        let mut writer = BufPosWriter::new(active_file)?;
-        writer.seek(SeekFrom::End(0))?;
-        let store = KvStore::new(writer, readers, idx, active_id, total_sz, path)?;
-        Ok(store)
-    }
-
-    fn compact(&mut self) -> Result<()> {
-        // To compact we first need to get a list of all files
-        // then, for every file, we take its ID --> find it's reader, remove from map
-        // close (if applicable)
-        // close writer (& fush)
-        // delete all files, create new file, replace writer
-        self.active_id += 1;
-        self.total_sz = 0;
-        let file_path = log_path(&self.path, self.active_id);
+        let job = self.shared.seal(&mut writer)?;
+        let (tx, rx) = mpsc::channel();
+        self.compactor.submit(job, Some(tx));
+        drop(writer);
+        rx.recv().expect("compaction thread exited")
+    }
+
+    /// Apply every operation of `batch` at once, after a crash either all of
+    /// them or none are replayed
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        self.write_locked(&mut writer, batch)
+    }
+
+    /// Iterate over the keys in `range` and their values, in key order. The
+    /// keys are the ones that existed when `scan` was called, values are read
+    /// as the iterator advances.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvStore, KvsEngine};
+    /// let store = KvStore::open("/tmp/store")?;
+    /// for pair in store.scan(b"a".to_vec()..b"c".to_vec()) {
+    ///     let (key, value) = pair?;
+    ///     println!("{:?} = {:?}", key, value);
+    /// }
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan {
+        self.shared.keydir.read().unwrap().scan(range)
+    }
+
+    /// Like `scan`, over the keys starting with `prefix`
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Scan {
+        self.scan(prefix_range(prefix))
+    }
+
+    /// Take a read-only view of the store as it is now, see `Snapshot`
+    pub fn snapshot(&self) -> Snapshot {
+        let keydir = self.shared.keydir.read().unwrap();
+        self.shared.pin(&keydir);
+        Snapshot::new(self.shared.clone(), keydir.live_copy())
+    }
+
+    /// Handle to the column family `name`: a key space of its own, with its own
+    /// index, logs and compaction under `<dir>/cf/<name>`. It is created on
+    /// first use and opened with the options of this store, a read-only store
+    /// sees one that does not exist as empty. Names are made of ASCII letters,
+    /// digits, `-` and `_`.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvStore, KvsEngine};
+    /// let store = KvStore::open("/tmp/store")?;
+    /// let users = store.column_family("users")?;
+    /// users.set("alice".to_owned(), "admin".to_owned())?;
+    /// assert_eq!(store.get("alice".to_owned())?, None);
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn column_family(&self, name: &str) -> Result<KvStore> {
+        if !is_cf_name(name) {
+            return Err(KvsError::Store(ErrorKind::InvalidColumnFamily));
+        }
+        let mut families = self.shared.families.lock().unwrap();
+        match families.entry(name.to_owned()) {
+            Entry::Occupied(e) => Ok(e.get().clone()),
+            Entry::Vacant(e) => {
+                let path = self.shared.path.join(CF_DIR).join(name);
+                let options = self.shared.options.clone();
+                let cf = if options.read_only && !path.exists() {
+                    // nothing was ever written to it, the same as a family
+                    // without logs
+                    KvStore::start(path, options, KeyDir::default(), None, None)?
+                } else {
+                    KvStore::open_with(path, options)?
+                };
+                Ok(e.insert(cf).clone())
+            }
+        }
+    }
+
+    /// Names of the column families found in the directory of the store
+    pub fn column_families(&self) -> Result<Vec<String>> {
+        let dir = self.shared.path.join(CF_DIR);
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            match entry.file_name().into_string() {
+                Ok(name) if entry.path().is_dir() && is_cf_name(&name) => names.push(name),
+                _ => {}
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    /// Start an optimistic transaction, see `Transaction`
+    pub fn transaction(&self) -> Transaction {
+        let seq = self.shared.keydir.read().unwrap().seq;
+        Transaction::new(self.clone(), seq)
+    }
+
+    /// Apply the writes of a transaction started at `seq` unless one of the
+    /// keys it read was written since
+    pub(crate) fn commit(
+        &self,
+        seq: u64,
+        reads: &HashSet<Vec<u8>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        // holding the writer lock keeps other writes out until ours is applied
+        let mut writer = self.lock_writer()?;
+        let keydir = self.shared.keydir.read().unwrap();
+        if reads.iter().any(|key| keydir.modified_since(key, seq)) {
+            return Err(KvsError::Store(ErrorKind::TransactionConflict));
+        }
+        drop(keydir);
+        self.write_locked(&mut writer, batch)
+    }
+
+    fn write_locked(&self, writer: &mut Writer, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.apply(writer, Record::Batch(batch.records))
+    }
+
+    /// Set (`new` is `Some`) or remove (`new` is `None`) a key, but only if its
+    /// current value is `expected`, `None` meaning that the key does not exist.
+    /// Returns whether the swap happened.
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        // no other write gets in between the comparison and the swap
+        let mut writer = self.lock_writer()?;
+        if self.get_bytes(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        let key = key.to_vec();
+        match new {
+            Some(value) => {
+                let expires_at = None;
+                self.apply(
+                    &mut writer,
+                    Record::Set {
+                        key,
+                        value,
+                        expires_at,
+                        seq: 0,
+                    },
+                )?
+            }
+            None if expected.is_some() => self.apply(&mut writer, Record::Rm { key, seq: 0 })?,
+            // expected to be missing and to stay that way
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Sequence number of the last write, a later `get_at` with it reads the
+    /// store as it is now
+    pub fn seq(&self) -> u64 {
+        self.shared.keydir.read().unwrap().seq
+    }
+
+    /// Retrieve the value `key` had right after the write with sequence number
+    /// `seq`. Versions overwritten or removed before the last compaction are
+    /// gone, reading one fails with `ErrorKind::VersionCompacted`.
+    pub fn get_at(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        let found = self.shared.keydir.read().unwrap().locate_at(key, seq)?;
+        match found {
+            Some((p, log)) => log.value(&p).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Store a value under the given key that is gone once `ttl` elapsed,
+    /// overwriting any previous value
+    pub fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        // a TTL too long to represent never runs out
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = Some(now_millis().saturating_add(ttl));
+        let mut writer = self.lock_writer()?;
+        self.apply(
+            &mut writer,
+            Record::Set {
+                key,
+                value,
+                expires_at,
+                seq: 0,
+            },
+        )
+    }
+
+    /// Time left before `key` expires, `None` when it does not expire. Fails
+    /// with `ErrorKind::NotFound` when the key does not exist.
+    pub fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let keydir = self.shared.keydir.read().unwrap();
+        let entry = keydir
+            .live(key)
+            .ok_or(KvsError::Store(ErrorKind::NotFound))?;
+        Ok(entry
+            .expires_at
+            .map(|at| Duration::from_millis(at.saturating_sub(now_millis()))))
+    }
+
+    /// Store a value only if the key does not exist yet, returns whether it
+    /// was stored
+    pub fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap(&key, None, Some(value))
+    }
+
+    /// Remove a key only if it holds `expected`, returns whether it was removed
+    pub fn remove_if_equals(&self, key: &[u8], expected: &[u8]) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    /// Lock the write path, fails with `ErrorKind::ReadOnly` when the store was
+    /// opened read-only
+    fn lock_writer(&self) -> Result<MutexGuard<'_, Writer>> {
+        if self.shared.options.read_only {
+            return Err(KvsError::Store(ErrorKind::ReadOnly));
+        }
+        Ok(self.shared.writer.lock().unwrap())
+    }
+
+    /// Append `record` to the active log and apply it to the index, the caller
+    /// holds the writer lock
+    fn apply(&self, writer: &mut Writer, mut record: Record) -> Result<()> {
+        record.stamp(self.shared.keydir.read().unwrap().seq + 1);
+        let pos = writer.append(&record.encode())?;
+        self.shared.keydir.write().unwrap().record(record, pos);
+        writer.sync_after_write()?;
+        self.maybe_compact(writer)
+    }
+
+    /// Start a background compaction when the policy asks for it, called after
+    /// every write with the writer lock still held
+    fn maybe_compact(&self, writer: &mut Writer) -> Result<()> {
+        if !writer.compacting && self.shared.should_compact() {
+            let job = self.shared.seal(writer)?;
+            self.compactor.submit(job, None);
+        }
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn should_compact(&self) -> bool {
+        let mut keydir = self.keydir.write().unwrap();
+        keydir.expire(now_millis());
+        let stale = keydir.stale_bytes();
+        match self.options.compaction {
+            CompactionPolicy::StaleBytes(threshold) => stale >= threshold,
+            CompactionPolicy::StaleRatio { ratio, min_stale } => {
+                stale >= min_stale && stale as f64 >= ratio * keydir.total_bytes() as f64
+            }
+            CompactionPolicy::Manual => false,
+        }
+    }
+
+    /// Seal every log file and describe the compaction of their live records.
+    /// The output gets the next id and writes move to the one after, so
+    /// replaying in id order still sees the newest value last.
+    fn seal(&self, writer: &mut Writer) -> Result<Job> {
+        let out_id = writer.active_id + 1;
+        // every handle is opened before writes move on, so a failure leaves
+        // the store as it was and compaction can be tried again
+        let prev = if self.options.mmap {
+            // the previous active log is sealed now, it is compacted away
+            // eventually but is read from until then
+            Some(LogFile::open(&log_path(&self.path, out_id - 1), true)?)
+        } else {
+            None
+        };
+        let active = writer.rotate(&self.path, out_id + 1)?;
+        writer.compacting = true;
+
+        let mut keydir = self.keydir.write().unwrap();
+        let sealed = keydir.files.keys().copied().collect::<Vec<usize>>();
+        keydir.files.insert(writer.active_id, FileStats::default());
+        keydir.logs.insert(writer.active_id, Arc::new(active));
+        if let Some(prev) = prev {
+            keydir.logs.insert(out_id - 1, Arc::new(prev));
+        }
+        let entries = keydir
+            .entries
+            .iter()
+            .filter_map(|(key, versions)| {
+                let v = versions.last().filter(|v| !v.removed)?;
+                Some(LiveKey {
+                    key: key.clone(),
+                    pos: v.pos,
+                    expires_at: v.expires_at,
+                    seq: v.seq,
+                })
+            })
+            .collect::<Vec<LiveKey>>();
+        Ok(Job {
+            dir: self.path.clone(),
+            out_id,
+            sealed,
+            entries,
+            seq: keydir.seq,
+        })
+    }
+
+    /// Point the index at the new generation and drop the sealed log files
+    /// along with the versions only they held. Keys written while the
+    /// compaction ran keep their newer version, the copy of the older one in
+    /// the new generation is stale from the start.
+    pub(crate) fn finish_compaction(&self, done: Compacted) -> Result<()> {
+        let out = match LogFile::open(&log_path(&self.path, done.out_id), self.options.mmap) {
+            Ok(out) => out,
+            Err(e) => {
+                compaction::discard_output(&self.path, done.out_id);
+                return Err(e);
+            }
+        };
+        {
+            let mut keydir = self.keydir.write().unwrap();
+            keydir.logs.insert(done.out_id, Arc::new(out));
+            keydir.files.insert(
+                done.out_id,
+                FileStats {
+                    len: done.sz as u64,
+                    stale: 0,
+                },
+            );
+            for (old, live) in done.moved {
+                let versions = match keydir.entries.get_mut(&live.key) {
+                    Some(versions) => versions,
+                    None => continue,
+                };
+                let latest = versions.last().is_some_and(|v| v.pos == old);
+                if let Some(v) = versions.iter_mut().find(|v| v.pos == old) {
+                    v.pos = live.pos;
+                }
+                if !latest {
+                    keydir.mark_stale(live.pos);
+                } else if let Some(at) = live.expires_at {
+                    match keydir.expiring.remove(&expiry_key(at, old)) {
+                        Some(sz) => {
+                            keydir.expiring.insert(expiry_key(at, live.pos), sz);
+                        }
+                        // expired while being copied
+                        None => keydir.mark_stale(live.pos),
+                    }
+                }
+            }
+            // whatever still points into the sealed logs was overwritten,
+            // removed or expired and was not copied
+            let sealed = &done.sealed;
+            for versions in keydir.entries.values_mut() {
+                versions.retain(|v| !sealed.contains(&v.pos.f_id));
+            }
+            keydir.entries.retain(|_, versions| !versions.is_empty());
+            keydir
+                .expiring
+                .retain(|&(_, f_id, _), _| !sealed.contains(&f_id));
+            keydir.compacted_seq = done.seq;
+            // nothing points into the sealed logs anymore, reads still in
+            // flight keep their handle open until they are done
+            for id in &done.sealed {
+                keydir.files.remove(id);
+                keydir.logs.remove(id);
+            }
+        }
+        let mut pins = self.pins.lock().unwrap();
+        for id in done.sealed {
+            // a hint is useless without its log, remove it first
+            remove_if_exists(&hint_path(&self.path, id))?;
+            if pins.counts.contains_key(&id) {
+                // a snapshot still reads from it, moved out of the way so
+                // replay never sees it again
+                std::fs::rename(log_path(&self.path, id), retired_path(&self.path, id))?;
+                pins.retired.insert(id);
+            } else {
+                std::fs::remove_file(log_path(&self.path, id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep compaction from deleting the logs `keydir` points into, the caller
+    /// holds the keydir lock so none of them is being deleted right now
+    fn pin(&self, keydir: &KeyDir) {
+        let mut pins = self.pins.lock().unwrap();
+        for id in keydir.logs.keys() {
+            *pins.counts.entry(*id).or_default() += 1;
+        }
+    }
+
+    /// Undo `pin`, deleting the logs compaction retired in the meantime
+    pub(crate) fn unpin(&self, keydir: &KeyDir) {
+        let mut pins = self.pins.lock().unwrap();
+        for id in keydir.logs.keys() {
+            let count = pins.counts.get_mut(id).expect("log was not pinned");
+            *count -= 1;
+            if *count > 0 {
+                continue;
+            }
+            pins.counts.remove(id);
+            if pins.retired.remove(id) {
+                if let Err(e) = std::fs::remove_file(retired_path(&self.path, *id)) {
+                    warn!("failed to remove retired log {}: {}", id, e);
+                }
+            }
+        }
+    }
+
+    /// Let writers (and `compact_now`) know the compaction in flight is over
+    pub(crate) fn compaction_finished(&self) {
+        self.writer.lock().unwrap().compacting = false;
+        self.compaction_done.notify_all();
+    }
+}
+
+/// Read handle of a log file
+pub(crate) enum LogFile {
+    File(File),
+    /// Sealed logs never change again, so with `OpenOptions::mmap` they are
+    /// mapped and records are sliced out of the mapping
+    Mmap(Mmap),
+}
+
+impl LogFile {
+    fn open(path: &Path, mmap: bool) -> Result<LogFile> {
+        let f = File::open(path)?;
+        // empty files cannot be mapped on every platform
+        if !mmap || f.metadata()?.len() == 0 {
+            return Ok(LogFile::File(f));
+        }
+        // SAFETY: only sealed logs are mapped, the store never writes to or
+        // truncates them again and deletes them only once they are unused
+        let map = unsafe { Mmap::map(&f)? };
+        Ok(LogFile::Mmap(map))
+    }
+
+    /// The raw record at `pos`
+    fn read(&self, pos: &CmdPos) -> Result<Cow<'_, [u8]>> {
+        match self {
+            LogFile::File(f) => {
+                let mut buf = vec![0u8; pos.sz];
+                read_exact_at(f, &mut buf, pos.pos as u64)?;
+                Ok(Cow::Owned(buf))
+            }
+            LogFile::Mmap(map) => {
+                map.get(pos.pos..pos.pos + pos.sz)
+                    .map(Cow::Borrowed)
+                    .ok_or(KvsError::Store(ErrorKind::Corruption {
+                        file: pos.f_id,
+                        offset: pos.pos as u64,
+                    }))
+            }
+        }
+    }
+
+    /// The value of the `Set` record at `pos`
+    pub(crate) fn value(&self, pos: &CmdPos) -> Result<Vec<u8>> {
+        match Record::decode(&self.read(pos)?) {
+            Some(Record::Set { value, .. }) => Ok(value),
+            Some(_) => Err(KvsError::Store(ErrorKind::UnsupportedCommand)),
+            None => Err(KvsError::Store(ErrorKind::Corruption {
+                file: pos.f_id,
+                offset: pos.pos as u64,
+            })),
+        }
+    }
+}
+
+/// Where the value of a key is, the log handle keeps the file readable even if
+/// compaction removes it in the meantime
+pub(crate) type ScanEntry = (CmdPos, Arc<LogFile>);
+
+/// Iterator over key/value pairs returned by `KvStore::scan` and
+/// `KvStore::scan_prefix`
+pub struct Scan {
+    entries: std::vec::IntoIter<(Vec<u8>, ScanEntry)>,
+}
+
+impl Iterator for Scan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, (pos, log)) = self.entries.next()?;
+        Some(log.value(&pos).map(|value| (key, value)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl Writer {
+    /// Append an encoded record to the active log, it is readable (but not
+    /// necessarily durable) once this returns
+    fn append(&mut self, record: &[u8]) -> Result<CmdPos> {
+        let writer = self.log();
+        let pos = writer.pos;
+        writer.write_all(record)?;
+        writer.flush()?;
+        Ok(CmdPos {
+            f_id: self.active_id,
+            pos,
+            sz: record.len(),
+        })
+    }
+
+    /// Apply the sync policy after a record has been appended
+    fn sync_after_write(&mut self) -> Result<()> {
+        self.unsynced += 1;
+        let due = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n,
+            SyncPolicy::Interval(d) => self.last_sync.elapsed() >= d,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.log().sync()?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Seal the active log and continue in a new one, returned with a read
+    /// handle on it. Writes still go to the old log when this fails.
+    fn rotate(&mut self, dir: &Path, id: usize) -> Result<LogFile> {
+        self.sync()?;
         let f = std::fs::OpenOptions::new()
             .read(true)
             .append(true)
-            .write(true)
             .create(true)
-            .open(&file_path)?;
-
-        self.writer = BufPosWriter::new(f)?;
-        self.writer.seek(SeekFrom::End(0))?;
-
-        for v in self.idx.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&v.f_id)
-                .expect("could not get reader associated with key");
-            let mut buf = vec![0u8; v.sz];
-            reader.seek(SeekFrom::Start(v.pos as u64))?;
-            reader.read(&mut buf)?;
-            let pos = self.writer.pos;
-            let sz = self.writer.write(&buf)?;
-            *v = CmdPos {
-                f_id: self.active_id,
-                pos,
-                sz,
-            };
-            self.writer.flush()?;
-            self.total_sz += sz;
-        }
-
-        let files = self
-            .readers
-            .keys()
-            .map(|id| log_path(&self.path, *id))
-            .collect::<Vec<PathBuf>>();
-
-        self.readers = HashMap::new();
-        let f = File::open(&file_path)?;
-        let mut reader = BufPosReader::new(f)?;
-        reader.seek(SeekFrom::Start(0))?;
-        self.readers.insert(self.active_id, reader);
-
-        for file in files {
-            std::fs::remove_file(file)?;
+            .open(log_path(dir, id))?;
+        let log = LogFile::open(&log_path(dir, id), false)?;
+        let mut writer = BufPosWriter::new(f)?;
+        writer.seek(SeekFrom::End(0))?;
+        self.writer = Some(writer);
+        self.active_id = id;
+        Ok(log)
+    }
+
+    fn log(&mut self) -> &mut BufPosWriter<File> {
+        self.writer.as_mut().expect("read-only stores never write")
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if self.unsynced > 0 && self.sync != SyncPolicy::Never {
+            if let Err(e) = self.sync() {
+                warn!("failed to sync the active log on close: {}", e);
+            }
         }
+    }
+}
 
-        Ok(())
+/// Handle to the thread that syncs the active log once per interval under
+/// `SyncPolicy::Interval`, so the last writes before a quiet period do not
+/// wait for the next write to reach the disk
+struct Syncer {
+    stop: Mutex<Option<Sender<()>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    fn spawn(shared: Arc<Shared>) -> Result<Self> {
+        let interval = match shared.options.sync {
+            // with a zero interval every write syncs already
+            SyncPolicy::Interval(d) if !shared.options.read_only && d > Duration::ZERO => d,
+            _ => {
+                return Ok(Syncer {
+                    stop: Mutex::new(None),
+                    handle: None,
+                })
+            }
+        };
+        let (stop, stop_rx) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("kvs-sync".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                    let mut writer = shared.writer.lock().unwrap();
+                    if writer.unsynced > 0 {
+                        if let Err(e) = writer.sync() {
+                            warn!("failed to sync the active log: {}", e);
+                        }
+                    }
+                }
+            })?;
+        Ok(Syncer {
+            stop: Mutex::new(Some(stop)),
+            handle: Some(handle),
+        })
     }
 }
 
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        // closing the channel wakes the thread up and stops it
+        self.stop.get_mut().unwrap().take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                warn!("sync thread panicked");
+            }
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Open the store found in `path` with the default `OpenOptions`
+    fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        OpenOptions::new().open(path)
+    }
+
+    /// Retrieve a variable from the KvStore and return as an Option<Vec<u8>> depending on whether
+    /// the key exists
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let found = self.shared.keydir.read().unwrap().locate(key);
+        match found {
+            Some((p, log)) => log.value(&p).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Store a value inside the KvStore using a key that can be subsequently used to retrieve
+    /// the value
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        let expires_at = None;
+        self.apply(
+            &mut writer,
+            Record::Set {
+                key,
+                value,
+                expires_at,
+                seq: 0,
+            },
+        )
+    }
+
+    /// Remove a variable from the KvStore
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        if self.shared.keydir.read().unwrap().live(key).is_none() {
+            return Err(KvsError::Store(ErrorKind::NotFound));
+        }
+        let key = key.to_owned();
+        self.apply(&mut writer, Record::Rm { key, seq: 0 })
+    }
+}
+
+fn is_cf_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Range of the keys starting with `prefix`
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let end = match prefix_end(prefix) {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_vec()), end)
+}
+
+/// Smallest key greater than every key starting with `prefix`, `None` when
+/// there is none
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Rebuild the index from the logs found in `dir`, returns it with the id of
+/// the newest log
+fn load_logs(dir: &Path, options: &OpenOptions) -> Result<(KeyDir, Option<usize>)> {
+    let files = log_ids(dir)?;
+    let last_id = files.last().copied();
+    let mut keydir = KeyDir::default();
+    for f_id in files {
+        let file_path = log_path(dir, f_id);
+        let len = std::fs::metadata(&file_path)?.len();
+        // only the active log can have been interrupted mid-write
+        let is_active = Some(f_id) == last_id;
+        if let Some(hint) = load_hint(dir, f_id, len)? {
+            for live in hint.entries {
+                keydir.record_set(live.key, live.pos, live.expires_at, live.seq);
+            }
+            // only the hint tells that older versions were dropped, a log
+            // replayed instead looks like the whole history. The removals
+            // and expired keys compaction dropped may have been the last
+            // writes, the counter must not go back past them.
+            keydir.compacted_seq = keydir.compacted_seq.max(hint.seq);
+            keydir.seq = keydir.seq.max(hint.seq);
+        } else {
+            let mut reader = BufPosReader::new(File::open(&file_path)?)?;
+            let end = replay(&mut reader, &mut keydir, f_id, is_active)?;
+            if (end as u64) < len && !options.read_only {
+                warn!(
+                    "truncating torn write at the tail of {}: {} bytes dropped",
+                    file_path.display(),
+                    len - end as u64
+                );
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&file_path)?
+                    .set_len(end as u64)?;
+            }
+        }
+        keydir.files.entry(f_id).or_default();
+        let log = LogFile::open(&file_path, options.mmap && !is_active)?;
+        keydir.logs.insert(f_id, Arc::new(log));
+    }
+    Ok((keydir, last_id))
+}
+
+/// Rebuild the index from a log file, failing with `ErrorKind::Corruption` on
+/// the first record that cannot be trusted. A record that looks like an append
+/// cut off by a crash, followed by nothing but zeros, is only accepted when
+/// `allow_torn` is set, replay then stops there and the returned offset (the
+/// end of the last complete record) is smaller than the file.
 fn replay(
     r: &mut BufPosReader<File>,
-    idx: &mut HashMap<String, CmdPos>,
+    keydir: &mut KeyDir,
     f_id: usize,
+    allow_torn: bool,
 ) -> Result<usize> {
-    let pos = r.seek(SeekFrom::Start(0))?;
-    let mut pos = pos as usize;
-    let mut stream = serde_json::Deserializer::from_reader(r).into_iter::<Command>();
-    while let Some(value) = stream.next() {
-        let value = value?;
-        // offset represents how many bytes have been read so far
-        let offset = stream.byte_offset();
-        if let Command::Set { key, .. } = value {
-            idx.insert(
-                key,
-                CmdPos {
-                    f_id,
-                    pos,
-                    sz: offset - pos,
-                },
-            );
-        } else if let Command::Rm { key } = value {
-            idx.remove(&key);
+    let mut pos = r.seek(SeekFrom::Start(0))? as usize;
+    loop {
+        match record::read_next(r)? {
+            Next::Record(record, sz) => {
+                keydir.record(record, CmdPos { f_id, pos, sz });
+                pos += sz;
+            }
+            Next::End => break,
+            // a crash can leave the file longer than the data that reached
+            // the disk, the tail then reads back as zeros
+            Next::Torn if allow_torn && only_zeros_left(r)? => break,
+            Next::Torn | Next::Corrupt => {
+                return Err(KvsError::Store(ErrorKind::Corruption {
+                    file: f_id,
+                    offset: pos as u64,
+                }))
+            }
         }
-        pos = offset;
     }
 
     Ok(pos)
 }
 
-struct BufPosWriter<W: Write + Seek> {
+/// Whether nothing but zeros is left to read from `r`
+fn only_zeros_left<R: Read>(r: &mut R) -> Result<bool> {
+    let mut buf = [0u8; 4096];
+    loop {
+        match r.read(&mut buf)? {
+            0 => return Ok(true),
+            n if buf[..n].iter().any(|&b| b != 0) => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+pub(crate) struct BufPosWriter<W: Write + Seek> {
     writer: BufWriter<W>,
-    pos: usize,
+    pub pos: usize,
 }
 
 impl<W: Write + Seek> BufPosWriter<W> {
-    fn new(mut f: W) -> Result<Self> {
-        let pos = f.seek(SeekFrom::Current(0))? as usize;
+    pub fn new(mut f: W) -> Result<Self> {
+        let pos = f.stream_position()? as usize;
         Ok(BufPosWriter {
             writer: BufWriter::new(f),
             pos,
@@ -296,6 +1252,15 @@ impl<W: Write + Seek> BufPosWriter<W> {
     }
 }
 
+impl BufPosWriter<File> {
+    /// Flush the buffer and `fsync` the file data
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
 impl<W: Write + Seek> Write for BufPosWriter<W> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let bytes = self.writer.write(buf)?;
@@ -315,13 +1280,13 @@ impl<W: Write + Seek> Seek for BufPosWriter<W> {
     }
 }
 
-struct BufPosReader<R: Read + Seek> {
+pub(crate) struct BufPosReader<R: Read + Seek> {
     reader: BufReader<R>,
     pos: usize,
 }
 
 impl<R: Read + Seek> BufPosReader<R> {
-    fn new(mut f: R) -> Result<Self> {
+    pub fn new(mut f: R) -> Result<Self> {
         let pos = f.seek(SeekFrom::Start(0))? as usize;
         Ok(BufPosReader {
             reader: BufReader::new(f),
@@ -346,7 +1311,159 @@ impl<R: Read + Seek> Seek for BufPosReader<R> {
     }
 }
 
+/// Ids of the log files found in `dir`, sorted from oldest to newest
+fn log_ids(dir: &Path) -> Result<Vec<usize>> {
+    let mut ids = std::fs::read_dir(dir)?
+        .filter_map(std::io::Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && p.extension() == Some("log".as_ref()))
+        .filter_map(|p| p.file_stem()?.to_str()?.parse::<usize>().ok())
+        .collect::<Vec<usize>>();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Whether `dir` has `<id>-log.json` files, left by versions of the store
+/// before the binary format
+fn has_legacy_logs(dir: &Path) -> Result<bool> {
+    Ok(std::fs::read_dir(dir)?
+        .filter_map(std::io::Result::ok)
+        .any(|e| {
+            let name = e.file_name();
+            let name = name.to_string_lossy();
+            name.strip_suffix("-log.json")
+                .is_some_and(|id| id.parse::<usize>().is_ok())
+        }))
+}
+
+/// Take the exclusive lock on the directory of a store, it is released when the
+/// returned file is closed. Fails with `ErrorKind::StoreLocked` when another
+/// handle (in this process or not) holds it.
+fn lock_dir(dir: &Path) -> Result<File> {
+    let f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))?;
+    match f.try_lock_exclusive() {
+        Ok(()) => Ok(f),
+        Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+            Err(KvsError::Store(ErrorKind::StoreLocked))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Remove what is left of compactions interrupted before their output was
+/// renamed into place, and logs that were only kept for snapshots
+fn remove_tmp_files(dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        if path.extension() == Some(TMP_EXT.as_ref()) {
+            warn!("removing unfinished compaction output {}", path.display());
+            std::fs::remove_file(path)?;
+        } else if path.extension() == Some(RETIRED_EXT.as_ref()) {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Entries of the hint for log `f_id` when it has a valid one
+fn load_hint(dir: &Path, f_id: usize, log_len: u64) -> Result<Option<Hint>> {
+    let path = hint_path(dir, f_id);
+    let buf = match std::fs::read(&path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let hint = hint::decode(&buf, f_id, log_len);
+    if hint.is_none() {
+        warn!("ignoring invalid hint {}", path.display());
+    }
+    Ok(hint)
+}
+
+pub(crate) fn remove_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Milliseconds since the unix epoch, the unit of expiry timestamps
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Key of a version in `KeyDir::expiring`, ordered by expiry first
+fn expiry_key(expires_at: u64, pos: CmdPos) -> (u64, usize, usize) {
+    (expires_at, pos.f_id, pos.pos)
+}
+
+fn expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|at| at <= now)
+}
+
+/// Make renames and new files in `dir` durable
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories cannot be opened, let alone synced, on other platforms
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Fill `buf` from `offset` without touching the file cursor, so a handle can
+/// be shared by concurrent readers
+#[cfg(unix)]
+pub(crate) fn read_exact_at(f: &File, buf: &mut [u8], offset: u64) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    f.read_exact_at(buf, offset)?;
+    Ok(())
+}
+
+/// `seek_read` moves the cursor on windows, which no other read relies on
+#[cfg(windows)]
+pub(crate) fn read_exact_at(f: &File, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match f.seek_read(buf, offset)? {
+            0 => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Where compaction writes a new generation before it is complete
+pub(crate) fn tmp_path(dir: &Path, id: usize) -> PathBuf {
+    dir.join(format!("{}.{}", id, TMP_EXT))
+}
+
+/// Index of the compacted log with the same id, see `hint`
+pub(crate) fn hint_path(dir: &Path, id: usize) -> PathBuf {
+    dir.join(format!("{}.hint", id))
+}
+
+/// Compacted log still read by a snapshot
+fn retired_path(dir: &Path, id: usize) -> PathBuf {
+    dir.join(format!("{}.{}", id, RETIRED_EXT))
+}
+
 // Credit to pingcap guide
-fn log_path(dir: &Path, id: usize) -> PathBuf {
-    dir.join(format!("{}-log.json", id))
+pub(crate) fn log_path(dir: &Path, id: usize) -> PathBuf {
+    dir.join(format!("{}.log", id))
 }
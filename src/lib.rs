@@ -9,7 +9,25 @@
 ref: https://blog.guillaume-gomez.fr/articles/2020-03-12+Guide+on+how+to+write+documentation+for+a+Rust+crate
 guideline: https://rust-lang.github.io/api-guidelines/documentation.html
 */
+pub use batch::WriteBatch;
+pub use client::KvsClient;
+pub use engine::KvsEngine;
 pub use error::{ErrorKind, KvsError, Result};
-pub use kv::KvStore;
+pub use kv::{KvStore, Scan};
+pub use options::{CompactionPolicy, OpenOptions, SyncPolicy};
+pub use server::KvsServer;
+pub use snapshot::Snapshot;
+pub use transaction::Transaction;
+mod batch;
+mod client;
+mod compaction;
+mod engine;
 mod error;
+mod hint;
 mod kv;
+mod options;
+mod protocol;
+mod record;
+mod server;
+mod snapshot;
+mod transaction;
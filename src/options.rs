@@ -0,0 +1,157 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::{KvStore, Result};
+
+/// When the active log is `fsync`ed, i.e. how many acknowledged writes a power
+/// loss can take away
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyncPolicy {
+    /// Sync after every write
+    Always,
+    /// Sync once every `n` writes
+    EveryN(usize),
+    /// Sync on a write once the interval has elapsed since the last sync, and
+    /// on a timer when writes stop, so a write is on disk at most about one
+    /// interval after it was acknowledged
+    Interval(Duration),
+    /// Never sync explicitly and let the OS write pages back
+    Never,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy::Interval(Duration::from_secs(1))
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::EveryN(n) => write!(f, "every={}", n),
+            SyncPolicy::Interval(d) => write!(f, "interval={}", d.as_millis()),
+            SyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// Parses the `Display` form: `always`, `never`, `every=<writes>` or
+/// `interval=<millis>`
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid sync policy '{}'", s);
+        match s.split_once('=') {
+            None if s == "always" => Ok(SyncPolicy::Always),
+            None if s == "never" => Ok(SyncPolicy::Never),
+            Some(("every", n)) => match n.parse() {
+                Ok(0) | Err(_) => Err(invalid()),
+                Ok(n) => Ok(SyncPolicy::EveryN(n)),
+            },
+            Some(("interval", ms)) => ms
+                .parse()
+                .map(|ms| SyncPolicy::Interval(Duration::from_millis(ms)))
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// When the log is compacted. Only stale bytes, i.e. records of keys that
+/// were overwritten or removed since, count towards the triggers so stores
+/// with a lot of live data do not compact on every write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionPolicy {
+    /// Compact once this many stale bytes piled up
+    StaleBytes(u64),
+    /// Compact once stale bytes make up at least `ratio` (between 0 and 1) of
+    /// the logs, as long as there are at least `min_stale` of them
+    StaleRatio { ratio: f64, min_stale: u64 },
+    /// Never compact on its own, see `KvStore::compact_now`
+    Manual,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy::StaleBytes(1024 * 1024)
+    }
+}
+
+impl fmt::Display for CompactionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompactionPolicy::StaleBytes(n) => write!(f, "stale-bytes={}", n),
+            CompactionPolicy::StaleRatio { ratio, min_stale } => {
+                write!(f, "stale-ratio={},min-stale={}", ratio, min_stale)
+            }
+            CompactionPolicy::Manual => write!(f, "manual"),
+        }
+    }
+}
+
+/// Options used to open a `KvStore`, built the same way as
+/// `std::fs::OpenOptions`:
+///
+/// ```no_run
+/// # use kvs::{CompactionPolicy, OpenOptions, SyncPolicy};
+/// let store = OpenOptions::new()
+///     .sync_policy(SyncPolicy::Always)
+///     .compaction_policy(CompactionPolicy::StaleRatio {
+///         ratio: 0.5,
+///         min_stale: 64 * 1024,
+///     })
+///     .open("/tmp/store")?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions {
+    pub(crate) sync: SyncPolicy,
+    pub(crate) compaction: CompactionPolicy,
+    pub(crate) mmap: bool,
+    pub(crate) read_only: bool,
+}
+
+impl OpenOptions {
+    /// Options with every setting at its default
+    pub fn new() -> Self {
+        OpenOptions::default()
+    }
+
+    /// Set when writes are synced to disk, defaults to once a second
+    pub fn sync_policy(&mut self, policy: SyncPolicy) -> &mut Self {
+        self.sync = policy;
+        self
+    }
+
+    /// Set when stale records are compacted, defaults to once 1MiB piled up
+    pub fn compaction_policy(&mut self, policy: CompactionPolicy) -> &mut Self {
+        self.compaction = policy;
+        self
+    }
+
+    /// Memory-map sealed log files so `get` reads records straight out of the
+    /// mapping, the active log is always read from the file. Off by default.
+    pub fn mmap(&mut self, mmap: bool) -> &mut Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Open the store without ever writing to its directory: nothing is
+    /// created, truncated or compacted, the directory is not locked so a
+    /// writer can have it open at the same time, and writes fail with
+    /// `ErrorKind::ReadOnly`. The store is seen as it was when opened, a
+    /// directory without logs is an empty store. Off by default.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Open (or create) the store in `path` with these options
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path.into(), self.clone())
+    }
+}
@@ -0,0 +1,116 @@
+//! Messages exchanged between `kvs-client` and `kvs-server`.
+//!
+//! Every message travels in its own frame: a big-endian `u32` holding the
+//! payload length followed by the JSON encoded message. A connection starts
+//! with the client sending `Request::Handshake` with its protocol version, the
+//! server answers with `Response::Handshake` when it speaks that version and
+//! with `ErrorKind::UnsupportedVersion` (before closing the connection) when it
+//! does not. Keys and values travel as base64 strings.
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{self, prelude::*};
+
+use crate::{ErrorKind, KvsError, Result};
+
+/// Version of the protocol spoken by this build, bump it on every
+/// incompatible change to `Request` or `Response`
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Frames larger than this are rejected instead of allocated, this also stops
+/// a peer speaking something else (e.g. unframed JSON) from stalling us
+const MAX_FRAME_SZ: usize = 64 * 1024 * 1024;
+
+/// A request sent by a client, one per engine operation
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    Handshake {
+        version: u32,
+    },
+    Get {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+    Set {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+    },
+    Rm {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+/// The answer to a `Request`, `Ok` holds the value for a `Get` (and `None`
+/// for the other operations). Store errors keep their `ErrorKind` so clients
+/// can match on them, anything else travels as a message.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    Handshake { version: u32 },
+    Ok(#[serde(with = "base64_bytes::option")] Option<Vec<u8>>),
+    Store(ErrorKind),
+    Err(String),
+}
+
+/// Serde helpers encoding bytes as base64 strings, JSON would otherwise turn
+/// them into an array of numbers
+mod base64_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        base64::decode(s).map_err(D::Error::custom)
+    }
+
+    pub mod option {
+        use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+            match bytes {
+                Some(bytes) => s.serialize_some(&base64::encode(bytes)),
+                None => s.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+            match Option::<String>::deserialize(d)? {
+                Some(s) => base64::decode(s).map(Some).map_err(D::Error::custom),
+                None => Ok(None),
+            }
+        }
+    }
+}
+
+/// Write `msg` as a single length-prefixed frame and flush it
+pub fn write_frame<W: Write, T: Serialize>(w: &mut W, msg: &T) -> Result<()> {
+    let payload = serde_json::to_vec(msg)?;
+    if payload.len() > MAX_FRAME_SZ {
+        return Err(KvsError::Store(ErrorKind::FrameTooLarge));
+    }
+    w.write_all(&(payload.len() as u32).to_be_bytes())?;
+    w.write_all(&payload)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Read the next frame, `None` means the peer closed the connection cleanly
+/// between two frames
+pub fn read_frame<R: Read, T: DeserializeOwned>(r: &mut R) -> Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SZ {
+        return Err(KvsError::Store(ErrorKind::FrameTooLarge));
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
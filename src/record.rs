@@ -0,0 +1,397 @@
+//! On-disk format of the records appended to the log files.
+//!
+//! ```text
+//! +---------+---------+---------+-------------+-------------+-----+-------+
+//! | len u32 | crc u32 | kind u8 | key_len u32 | val_len u32 | key | value |
+//! +---------+---------+---------+-------------+-------------+-----+-------+
+//! ```
+//!
+//! Integers are little-endian, `len` counts the bytes that follow `crc` and
+//! `crc` is the CRC32 of those same bytes. Keys and values are arbitrary bytes,
+//! `Rm` records carry an empty value. A `Set` with an expiry uses its own kind
+//! and ends with an extra `expires_at u64`, the expiry in milliseconds since
+//! the unix epoch.
+//!
+//! Records end with the `seq u64` of the write, again with kinds of their own:
+//! records written before sequence numbers were logged are still read and get
+//! numbered in log order on replay.
+//!
+//! A batch is a single record wrapping complete `Set`/`Rm` records, so the
+//! outer checksum covers all of them and each one can still be read (and
+//! copied) on its own:
+//!
+//! ```text
+//! +---------+---------+---------+-----------+----------+----------+-----
+//! | len u32 | crc u32 | kind u8 | count u32 | record 0 | record 1 | ...
+//! +---------+---------+---------+-----------+----------+----------+-----
+//! ```
+use std::io::{self, prelude::*};
+
+/// Size of the `len` + `crc` prefix
+const HEADER_SZ: usize = 8;
+/// Size of `kind` + `key_len` + `val_len`
+const BODY_HEADER_SZ: usize = 9;
+
+const KIND_SET: u8 = 1;
+const KIND_RM: u8 = 2;
+const KIND_BATCH: u8 = 3;
+const KIND_SET_TTL: u8 = 4;
+const KIND_SET_SEQ: u8 = 5;
+const KIND_RM_SEQ: u8 = 6;
+const KIND_SET_TTL_SEQ: u8 = 7;
+
+/// Offset of the first record wrapped in a batch
+pub const BATCH_HEADER_SZ: usize = HEADER_SZ + 5;
+
+/// A single operation in the log
+///
+/// `seq` is the sequence number of the write, it is 0 until the record is
+/// stamped right before being appended and stays 0 for records logged before
+/// sequence numbers were.
+#[derive(Clone, Debug)]
+pub enum Record {
+    Set {
+        key: Vec<u8>,
+        /// Milliseconds since the unix epoch after which the key is gone
+        expires_at: Option<u64>,
+        value: Vec<u8>,
+        seq: u64,
+    },
+    Rm {
+        key: Vec<u8>,
+        seq: u64,
+    },
+    /// `Set` and `Rm` records applied all-or-nothing
+    Batch(Vec<Record>),
+}
+
+/// What was found when reading the next record of a log
+pub enum Next {
+    /// A valid record, along with its size on disk
+    Record(Record, usize),
+    /// Clean end of the log
+    End,
+    /// A record that can be the last append of the log cut off by a crash:
+    /// the log ends in the middle of it, or it ends in zeros, and what is
+    /// there agrees with its length
+    Torn,
+    /// A record that fails its checksum or cannot be decoded, and cannot be a
+    /// cut-off append either
+    Corrupt,
+}
+
+impl Record {
+    /// Encode the record, header included, ready to be appended to a log
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        buf.extend_from_slice(&[0u8; HEADER_SZ]);
+        match self {
+            Record::Set {
+                key,
+                value,
+                expires_at,
+                seq,
+            } => {
+                let kind = match (expires_at, seq) {
+                    (None, 0) => KIND_SET,
+                    (Some(_), 0) => KIND_SET_TTL,
+                    (None, _) => KIND_SET_SEQ,
+                    (Some(_), _) => KIND_SET_TTL_SEQ,
+                };
+                encode_body(&mut buf, kind, key, value);
+                if let Some(expires_at) = expires_at {
+                    buf.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                encode_seq(&mut buf, *seq);
+            }
+            Record::Rm { key, seq } => {
+                let kind = if *seq == 0 { KIND_RM } else { KIND_RM_SEQ };
+                encode_body(&mut buf, kind, key, &[]);
+                encode_seq(&mut buf, *seq);
+            }
+            Record::Batch(records) => {
+                buf.push(KIND_BATCH);
+                buf.extend_from_slice(&(records.len() as u32).to_le_bytes());
+                for record in records {
+                    buf.extend_from_slice(&record.encode());
+                }
+            }
+        }
+        let len = buf.len() - HEADER_SZ;
+        let crc = crc32fast::hash(&buf[HEADER_SZ..]);
+        buf[..4].copy_from_slice(&(len as u32).to_le_bytes());
+        buf[4..HEADER_SZ].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Size of the record once encoded, header included
+    pub fn encoded_len(&self) -> usize {
+        HEADER_SZ
+            + match self {
+                Record::Set {
+                    key,
+                    value,
+                    expires_at,
+                    seq,
+                } => {
+                    BODY_HEADER_SZ
+                        + key.len()
+                        + value.len()
+                        + expires_at.map_or(0, |_| 8)
+                        + seq_len(*seq)
+                }
+                Record::Rm { key, seq } => BODY_HEADER_SZ + key.len() + seq_len(*seq),
+                Record::Batch(records) => {
+                    BATCH_HEADER_SZ - HEADER_SZ
+                        + records.iter().map(Record::encoded_len).sum::<usize>()
+                }
+            }
+    }
+
+    /// Decode a full record as written by `encode`, `None` is returned when it
+    /// fails any of the integrity checks
+    pub fn decode(buf: &[u8]) -> Option<Record> {
+        if buf.len() < HEADER_SZ {
+            return None;
+        }
+        let (len, crc) = (u32_at(buf, 0) as usize, u32_at(buf, 4));
+        let body = &buf[HEADER_SZ..];
+        if body.len() != len || crc32fast::hash(body) != crc {
+            return None;
+        }
+        decode_body(body)
+    }
+
+    /// Sequence number of the record, the one of its first record for a batch
+    pub fn seq(&self) -> u64 {
+        match self {
+            Record::Set { seq, .. } | Record::Rm { seq, .. } => *seq,
+            Record::Batch(records) => records.first().map_or(0, Record::seq),
+        }
+    }
+
+    /// Give the record (every record of a batch) the sequence number `seq`
+    pub fn stamp(&mut self, seq: u64) {
+        match self {
+            Record::Set { seq: s, .. } | Record::Rm { seq: s, .. } => *s = seq,
+            Record::Batch(records) => records.iter_mut().for_each(|r| r.stamp(seq)),
+        }
+    }
+}
+
+/// Read the record starting at the current position of `r`
+pub fn read_next<R: Read>(r: &mut R) -> io::Result<Next> {
+    let mut header = [0u8; HEADER_SZ];
+    match read_full(r, &mut header)? {
+        0 => return Ok(Next::End),
+        n if n < HEADER_SZ => return Ok(Next::Torn),
+        _ => {}
+    }
+    let (len, crc) = (u32_at(&header, 0) as usize, u32_at(&header, 4));
+    // read through `take` so a garbage length does not allocate up front
+    let mut body = Vec::new();
+    let complete = r.by_ref().take(len as u64).read_to_end(&mut body)? == len;
+    if complete && crc32fast::hash(&body) == crc {
+        if let Some(record) = decode_body(&body) {
+            return Ok(Next::Record(record, HEADER_SZ + len));
+        }
+    }
+    Ok(if could_be_torn(&header, &body, len, complete) {
+        Next::Torn
+    } else {
+        Next::Corrupt
+    })
+}
+
+/// Whether a record that could not be read can be an append cut off by a
+/// crash. Either the log ends inside of it, or it ends in zeros because the
+/// file grew before the data reached the disk. In both cases the bytes that
+/// were written must agree with `len`, which a damaged length does not.
+fn could_be_torn(header: &[u8], body: &[u8], len: usize, complete: bool) -> bool {
+    let zeros = header
+        .iter()
+        .chain(body)
+        .rev()
+        .take_while(|&&b| b == 0)
+        .count();
+    if complete && zeros == 0 {
+        return false;
+    }
+    let written = HEADER_SZ + body.len() - zeros;
+    written < HEADER_SZ || fits_len(&body[..written - HEADER_SZ], len)
+}
+
+/// Whether `prefix` can be the start of a record body `len` bytes long, going
+/// by the lengths found in it
+fn fits_len(prefix: &[u8], len: usize) -> bool {
+    let kind = match prefix.first() {
+        Some(&KIND_BATCH) => return batch_fits_len(prefix, len),
+        Some(&kind) => kind,
+        None => return true,
+    };
+    let trailer_len = match trailer_fields(kind) {
+        Some((has_expiry, has_seq)) => 8 * has_expiry as usize + 8 * has_seq as usize,
+        None => return false,
+    };
+    if prefix.len() < BODY_HEADER_SZ {
+        return BODY_HEADER_SZ + trailer_len <= len;
+    }
+    let key_len = u32_at(prefix, 1) as usize;
+    let val_len = u32_at(prefix, 5) as usize;
+    BODY_HEADER_SZ + key_len + val_len + trailer_len == len
+}
+
+/// `fits_len` for a batch, the records found in full must add up to `len`
+/// and the one cut off must fit in what is left
+fn batch_fits_len(prefix: &[u8], len: usize) -> bool {
+    let mut pos = BATCH_HEADER_SZ - HEADER_SZ;
+    if prefix.len() < pos {
+        return pos <= len;
+    }
+    for _ in 0..u32_at(prefix, 1) {
+        if prefix.len() < pos + HEADER_SZ {
+            return pos + HEADER_SZ <= len;
+        }
+        let sub_len = u32_at(prefix, pos) as usize;
+        let end = pos + HEADER_SZ + sub_len;
+        if end > len {
+            return false;
+        }
+        if end > prefix.len() {
+            let sub = &prefix[pos + HEADER_SZ..];
+            // batches do not nest
+            return sub.first() != Some(&KIND_BATCH) && fits_len(sub, sub_len);
+        }
+        pos = end;
+    }
+    pos == len
+}
+
+fn encode_body(buf: &mut Vec<u8>, kind: u8, key: &[u8], value: &[u8]) {
+    buf.push(kind);
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+}
+
+fn encode_seq(buf: &mut Vec<u8>, seq: u64) {
+    if seq != 0 {
+        buf.extend_from_slice(&seq.to_le_bytes());
+    }
+}
+
+fn seq_len(seq: u64) -> usize {
+    if seq == 0 {
+        0
+    } else {
+        8
+    }
+}
+
+fn decode_body(body: &[u8]) -> Option<Record> {
+    if body.first() == Some(&KIND_BATCH) {
+        return decode_batch(body);
+    }
+    if body.len() < BODY_HEADER_SZ {
+        return None;
+    }
+    let kind = body[0];
+    let (has_expiry, has_seq) = trailer_fields(kind)?;
+    let key_len = u32_at(body, 1) as usize;
+    let val_len = u32_at(body, 5) as usize;
+    let end = BODY_HEADER_SZ + key_len + val_len;
+    let trailer_len = if has_expiry { 8 } else { 0 } + if has_seq { 8 } else { 0 };
+    if end + trailer_len != body.len() {
+        return None;
+    }
+    let key = body[BODY_HEADER_SZ..BODY_HEADER_SZ + key_len].to_vec();
+    let value = &body[BODY_HEADER_SZ + key_len..end];
+    let expires_at = if has_expiry {
+        Some(u64_at(body, end))
+    } else {
+        None
+    };
+    let seq = if has_seq {
+        u64_at(body, body.len() - 8)
+    } else {
+        0
+    };
+    match kind {
+        KIND_RM | KIND_RM_SEQ if value.is_empty() => Some(Record::Rm { key, seq }),
+        KIND_RM | KIND_RM_SEQ => None,
+        _ => Some(Record::Set {
+            key,
+            value: value.to_vec(),
+            expires_at,
+            seq,
+        }),
+    }
+}
+
+/// Whether records of `kind` end with an expiry and with a sequence number,
+/// `None` for an unknown kind
+fn trailer_fields(kind: u8) -> Option<(bool, bool)> {
+    match kind {
+        KIND_SET | KIND_RM => Some((false, false)),
+        KIND_SET_TTL => Some((true, false)),
+        KIND_SET_SEQ | KIND_RM_SEQ => Some((false, true)),
+        KIND_SET_TTL_SEQ => Some((true, true)),
+        _ => None,
+    }
+}
+
+fn decode_batch(body: &[u8]) -> Option<Record> {
+    let start = BATCH_HEADER_SZ - HEADER_SZ;
+    if body.len() < start {
+        return None;
+    }
+    let count = u32_at(body, 1) as usize;
+    let mut rest = &body[start..];
+    let mut records = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < HEADER_SZ || rest.len() < HEADER_SZ + u32_at(rest, 0) as usize {
+            return None;
+        }
+        let (record, tail) = rest.split_at(HEADER_SZ + u32_at(rest, 0) as usize);
+        match Record::decode(record)? {
+            // batches do not nest
+            Record::Batch(_) => return None,
+            record => records.push(record),
+        }
+        rest = tail;
+    }
+    if records.len() != count {
+        return None;
+    }
+    Some(Record::Batch(records))
+}
+
+/// Like `read_exact` but reports how much was read instead of failing at EOF
+fn read_full<R: Read>(r: &mut R, mut buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while !buf.is_empty() {
+        match r.read(buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                total += n;
+                buf = &mut buf[n..];
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+pub fn u32_at(buf: &[u8], at: usize) -> u32 {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&buf[at..at + 4]);
+    u32::from_le_bytes(b)
+}
+
+pub fn u64_at(buf: &[u8], at: usize) -> u64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&buf[at..at + 8]);
+    u64::from_le_bytes(b)
+}
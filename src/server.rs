@@ -0,0 +1,95 @@
+use log::{debug, error, info, warn};
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+
+use crate::protocol::{read_frame, write_frame, Request, Response, PROTOCOL_VERSION};
+use crate::{ErrorKind, KvsEngine, KvsError, Result};
+
+/// `KvsServer` owns a single engine and answers requests coming in over TCP,
+/// so clients no longer have to replay the log on every command.
+pub struct KvsServer<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    /// Create a server that serves requests using the given engine
+    pub fn new(engine: E) -> Self {
+        KvsServer { engine }
+    }
+
+    /// Bind to `addr` and serve every client on its own thread (with its own
+    /// clone of the engine) until the listener fails
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let engine = self.engine.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(engine, stream) {
+                            error!("error serving client: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("connection failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
+    let peer = tcp.peer_addr()?;
+    let mut reader = BufReader::new(&tcp);
+    let mut writer = BufWriter::new(&tcp);
+
+    if !handshake(&mut reader, &mut writer)? {
+        warn!("client {} failed the handshake", peer);
+        return Ok(());
+    }
+
+    while let Some(req) = read_frame::<_, Request>(&mut reader)? {
+        debug!("received request from {}: {:?}", peer, req);
+        let resp = match req {
+            Request::Get { key } => engine.get_bytes(&key).map(Response::Ok),
+            Request::Set { key, value } => engine.set_bytes(key, value).map(|_| Response::Ok(None)),
+            Request::Rm { key } => engine.remove_bytes(&key).map(|_| Response::Ok(None)),
+            Request::Handshake { .. } => Err(KvsError::Store(ErrorKind::UnsupportedCommand)),
+        }
+        .unwrap_or_else(|e| match e {
+            KvsError::Store(kind) => Response::Store(kind),
+            e => Response::Err(e.to_string()),
+        });
+        write_frame(&mut writer, &resp)?;
+        debug!("sent response to {}: {:?}", peer, resp);
+    }
+    info!("client {} disconnected", peer);
+    Ok(())
+}
+
+/// Agree on the protocol version, returns false (after telling the client why)
+/// when the connection should be dropped
+fn handshake(
+    reader: &mut BufReader<&TcpStream>,
+    writer: &mut BufWriter<&TcpStream>,
+) -> Result<bool> {
+    let resp = match read_frame::<_, Request>(reader) {
+        Ok(Some(Request::Handshake { version })) if version == PROTOCOL_VERSION => {
+            write_frame(writer, &Response::Handshake { version })?;
+            return Ok(true);
+        }
+        Ok(Some(Request::Handshake { version })) => {
+            Response::Store(ErrorKind::UnsupportedVersion {
+                client: version,
+                server: PROTOCOL_VERSION,
+            })
+        }
+        Ok(Some(req)) => Response::Err(format!("expected a handshake, got {:?}", req)),
+        Ok(None) => return Ok(false),
+        Err(KvsError::Store(kind)) => Response::Store(kind),
+        Err(e) => Response::Err(e.to_string()),
+    };
+    write_frame(writer, &resp)?;
+    Ok(false)
+}
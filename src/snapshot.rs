@@ -0,0 +1,72 @@
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+use crate::kv::{prefix_range, KeyDir, Shared};
+use crate::{Result, Scan};
+
+/// `Snapshot` is a read-only view of a store as it was when
+/// `KvStore::snapshot` was called.
+///
+/// Writes made after that are not visible, compaction keeps the log files the
+/// snapshot reads from until it is dropped. Keys with a TTL still expire while
+/// the snapshot is held.
+///
+/// Taking a snapshot copies every live key of the index, so it costs time and
+/// memory in proportion to the number of keys and holds writers back while
+/// the copy is made. Snapshots suit occasional long reads (reports, backups)
+/// rather than being taken for every request.
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine};
+/// let store = KvStore::open("/tmp/store")?;
+/// let snapshot = store.snapshot();
+/// store.set("key".to_owned(), "new".to_owned())?;
+/// // still the value from before the `set`
+/// let old = snapshot.get("key".to_owned())?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+pub struct Snapshot {
+    shared: Arc<Shared>,
+    keydir: KeyDir,
+}
+
+impl Snapshot {
+    /// `keydir` is a live copy of the index whose logs were pinned for the
+    /// snapshot
+    pub(crate) fn new(shared: Arc<Shared>, keydir: KeyDir) -> Self {
+        Snapshot { shared, keydir }
+    }
+
+    /// Retrieve the value a key had when the snapshot was taken
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.keydir.locate(key) {
+            Some((pos, log)) => log.value(&pos).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `get_bytes`, fails with `KvsError::Utf8` when the value is not
+    /// valid UTF-8
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `KvStore::scan`, over the keys as of the snapshot
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan {
+        self.keydir.scan(range)
+    }
+
+    /// Like `KvStore::scan_prefix`, over the keys as of the snapshot
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Scan {
+        self.scan(prefix_range(prefix))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.shared.unpin(&self.keydir);
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{ErrorKind, KvStore, KvsEngine, KvsError, Result, WriteBatch};
+
+/// `Transaction` is an optimistic read-modify-write transaction started with
+/// `KvStore::transaction`.
+///
+/// Reads go to the store (or to the transaction's own writes), writes are
+/// buffered until `commit`, which applies them as one batch. The commit fails
+/// with `ErrorKind::TransactionConflict`, without writing anything, when
+/// another writer set or removed one of the keys read since the transaction
+/// started.
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine};
+/// let store = KvStore::open("/tmp/store")?;
+/// let mut txn = store.transaction();
+/// let hits = txn.get("hits".to_owned())?.unwrap_or_default();
+/// let hits = hits.parse::<u64>().unwrap_or(0) + 1;
+/// txn.set("hits".to_owned(), hits.to_string());
+/// txn.commit()?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+pub struct Transaction {
+    store: KvStore,
+    // sequence number of the store when the transaction started
+    seq: u64,
+    reads: HashSet<Vec<u8>>,
+    // `None` marks a removal
+    writes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(store: KvStore, seq: u64) -> Self {
+        Transaction {
+            store,
+            seq,
+            reads: HashSet::new(),
+            writes: HashMap::new(),
+        }
+    }
+
+    /// Retrieve the value of a key, seeing the writes made by this transaction
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        self.reads.insert(key.to_vec());
+        self.store.get_bytes(key)
+    }
+
+    /// Store a value under the given key once the transaction commits
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Remove a key once the transaction commits, fails with
+    /// `ErrorKind::NotFound` when the key does not exist
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        if self.get_bytes(key)?.is_none() {
+            return Err(KvsError::Store(ErrorKind::NotFound));
+        }
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    /// Like `get_bytes`, fails with `KvsError::Utf8` when the value is not
+    /// valid UTF-8
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `set_bytes`
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Like `remove_bytes`
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Apply every write of the transaction at once, unless a key it read was
+    /// written by someone else since it started
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set_bytes(key, value),
+                None => batch.remove_bytes(key),
+            };
+        }
+        self.store.commit(self.seq, &self.reads, batch)
+    }
+}
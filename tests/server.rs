@@ -0,0 +1,235 @@
+use assert_cmd::prelude::*;
+use kvs::{ErrorKind, KvsClient, KvsError, Result};
+use predicates::ord::eq;
+use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Kills the server when a test finishes, even when it panics.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// Spawn `kvs-server` in `dir` and wait until it accepts connections on `addr`.
+fn spawn_server(dir: &TempDir, addr: &str) -> Server {
+    let server = Server(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr])
+            .current_dir(dir)
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..50 {
+        if TcpStream::connect(addr).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("server did not start listening on {}", addr);
+}
+
+// `kvs-server -V` should print the version
+#[test]
+fn server_cli_version() {
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["-V"])
+        .assert()
+        .stdout(contains(env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn server_cli_invalid_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "unknown"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn server_cli_invalid_addr() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "not-an-address"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Write a raw length-prefixed frame.
+fn send_frame(stream: &mut TcpStream, payload: &str) {
+    stream
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(payload.as_bytes()).unwrap();
+}
+
+// Read a raw length-prefixed frame.
+fn recv_frame(stream: &mut TcpStream) -> String {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut payload = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    String::from_utf8(payload).unwrap()
+}
+
+// A connection should be able to issue several requests against one store.
+#[test]
+fn server_serves_framed_requests() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4101";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    send_frame(&mut stream, r#"{"Handshake":{"version":2}}"#);
+    assert_eq!(recv_frame(&mut stream), r#"{"Handshake":{"version":2}}"#);
+    // keys and values are base64 encoded, "key1" and "value1" here
+    send_frame(
+        &mut stream,
+        r#"{"Set":{"key":"a2V5MQ==","value":"dmFsdWUx"}}"#,
+    );
+    assert_eq!(recv_frame(&mut stream), r#"{"Ok":null}"#);
+    send_frame(&mut stream, r#"{"Get":{"key":"a2V5MQ=="}}"#);
+    assert_eq!(recv_frame(&mut stream), r#"{"Ok":"dmFsdWUx"}"#);
+}
+
+// Clients speaking another protocol version get a clear error instead of hanging.
+#[test]
+fn server_rejects_unsupported_version() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4104";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    send_frame(&mut stream, r#"{"Handshake":{"version":1}}"#);
+    assert_eq!(
+        recv_frame(&mut stream),
+        r#"{"Store":{"UnsupportedVersion":{"client":1,"server":2}}}"#
+    );
+    // the server hangs up after a failed handshake
+    assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+}
+
+// Unframed JSON from a pre-versioning client must not stall the server.
+#[test]
+fn server_rejects_unframed_requests() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4105";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(br#"{"Get":{"key":"key1"}}"#).unwrap();
+    assert_eq!(recv_frame(&mut stream), r#"{"Store":"FrameTooLarge"}"#);
+    assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+}
+
+#[test]
+fn client_cli_invalid_subcommand() {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["unknown", "subcommand"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "missing_field"])
+        .assert()
+        .failure();
+}
+
+// `kvs-client` should behave like `kvs` when talking to a server.
+#[test]
+fn client_cli_access_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4102";
+    let _server = spawn_server(&temp_dir, addr);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    // `ErrorKind::NotFound` makes it back to the client and exits non-zero
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+}
+
+#[test]
+fn client_errors_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4103";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::Store(ErrorKind::NotFound)) => {}
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Binary keys and values survive the trip through the server.
+#[test]
+fn client_binary_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4106";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set_bytes(vec![0xff, 0x00], vec![0x00, 0xff])?;
+    assert_eq!(client.get_bytes(vec![0xff, 0x00])?, Some(vec![0x00, 0xff]));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "ff00", "--hex", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(eq("00ff").trim());
+    Ok(())
+}
@@ -1,7 +1,13 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    CompactionPolicy, ErrorKind, KvStore, KvsEngine, KvsError, OpenOptions, Result, SyncPolicy,
+    WriteBatch,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use tempfile::TempDir;
 use walkdir::WalkDir;
@@ -17,7 +23,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +34,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +47,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +60,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -65,14 +71,14 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +86,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -94,13 +100,13 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +114,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +127,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +142,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +163,45 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
+        .assert()
+        .failure();
+}
+
+// `kvs status` should report the sync policy in use.
+#[test]
+fn cli_status_sync_policy() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["status"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("sync policy: interval=1000"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--sync", "every=10", "status"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("sync policy: every=10"));
+}
+
+#[test]
+fn cli_invalid_sync_policy() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--sync", "sometimes"])
+        .current_dir(&temp_dir)
         .assert()
         .failure();
 }
@@ -172,7 +210,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -181,7 +219,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -191,7 +229,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -202,7 +240,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -211,7 +249,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -223,14 +261,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -239,7 +277,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -247,7 +285,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -259,7 +297,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -289,7 +327,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -299,3 +337,1535 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Log files of the store living in `dir`, oldest first.
+fn log_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension() == Some("log".as_ref()))
+        .collect();
+    files.sort();
+    files
+}
+
+// Compaction runs in the background, wait until the oldest log file is gone.
+fn wait_for_compaction(dir: &Path, oldest: &str) -> Result<()> {
+    for _ in 0..100 {
+        if log_files(dir)[0] != dir.join(oldest) {
+            return Ok(());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    panic!("No compaction detected");
+}
+
+// A record that fails its checksum should be reported when the store is reopened.
+#[test]
+fn corruption_detected_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // flip a byte of the first record's key
+    let log = &log_files(temp_dir.path())[0];
+    let mut bytes = fs::read(log)?;
+    bytes[18] ^= 0xff;
+    fs::write(log, bytes)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()).err(),
+        Some(KvsError::Store(ErrorKind::Corruption { offset: 0, .. }))
+    ));
+    Ok(())
+}
+
+// A record corrupted after the store was opened should be reported by `get`.
+#[test]
+fn corruption_detected_on_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let log = &log_files(temp_dir.path())[0];
+    let mut file = fs::OpenOptions::new().write(true).open(log)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    // overwrite the last byte of the value
+    file.seek(SeekFrom::Start(len - 1))?;
+    file.write_all(b"X")?;
+    drop(file);
+
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvsError::Store(ErrorKind::Corruption { offset: 0, .. }))
+    ));
+    Ok(())
+}
+
+// A record cut short at the end of the active log (e.g. a crash mid-write)
+// should be dropped on open instead of failing it.
+#[test]
+fn torn_write_truncated_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = log_files(temp_dir.path()).pop().unwrap();
+    let good_len = fs::metadata(&log)?.len();
+    // the header of a 40 byte record with only part of its body
+    let mut file = fs::OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&[40, 0, 0, 0, 1, 2, 3, 4, 1, 4, 0])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), good_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A tail of zeros, left when the file grew before its data reached the disk,
+// is dropped like a torn write, bad records before valid ones are not.
+#[test]
+fn zeroed_tail_truncated_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = log_files(temp_dir.path()).pop().unwrap();
+    let good_len = fs::metadata(&log)?.len();
+    let mut file = fs::OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&[0; 16])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), good_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // zeros in the middle of the log are corruption
+    let mut bytes = fs::read(&log)?;
+    let tail = bytes.split_off(good_len as usize / 2);
+    bytes.extend_from_slice(&[0; 16]);
+    bytes.extend_from_slice(&tail);
+    fs::write(&log, bytes)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()).err(),
+        Some(KvsError::Store(ErrorKind::Corruption { .. }))
+    ));
+    Ok(())
+}
+
+// A directory with logs of the old JSON format should not open as an empty
+// store.
+#[test]
+fn legacy_logs_rejected_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("0-log.json"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )?;
+    for read_only in [false, true] {
+        assert!(matches!(
+            OpenOptions::new()
+                .read_only(read_only)
+                .open(temp_dir.path())
+                .err(),
+            Some(KvsError::Store(ErrorKind::LegacyLogFormat))
+        ));
+    }
+    assert!(log_files(temp_dir.path()).is_empty());
+    Ok(())
+}
+
+// A damaged length in the middle of the active log is corruption, not a torn
+// write, and must not get the records after it truncated away.
+#[test]
+fn corrupt_length_detected_on_open() -> Result<()> {
+    // low and high byte of the length of the second record
+    for byte in [0, 3] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        for i in 0..5 {
+            store.set(format!("k{}", i), format!("v{}", i))?;
+        }
+        drop(store);
+
+        let log = log_files(temp_dir.path()).pop().unwrap();
+        let mut bytes = fs::read(&log)?;
+        let len = bytes.len();
+        bytes[len / 5 + byte] = 0x7f;
+        fs::write(&log, bytes)?;
+        assert!(matches!(
+            KvStore::open(temp_dir.path()).err(),
+            Some(KvsError::Store(ErrorKind::Corruption { offset, .. })) if offset == len as u64 / 5
+        ));
+        assert_eq!(fs::metadata(&log)?.len(), len as u64);
+    }
+    Ok(())
+}
+
+// Every sync policy should persist writes across reopening the store.
+#[test]
+fn sync_policies_persist_writes() -> Result<()> {
+    let policies = [
+        SyncPolicy::Always,
+        SyncPolicy::EveryN(2),
+        SyncPolicy::Interval(std::time::Duration::from_millis(0)),
+        SyncPolicy::Never,
+    ];
+    for policy in policies.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = OpenOptions::new()
+            .sync_policy(*policy)
+            .open(temp_dir.path())?;
+        assert_eq!(store.sync_policy(), *policy);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// The thread syncing on an interval should not hold up closing the store.
+#[test]
+fn interval_sync_stops_on_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .sync_policy(SyncPolicy::Interval(std::time::Duration::from_secs(60)))
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let start = std::time::Instant::now();
+    drop(store);
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Live data alone should never trigger a compaction, only stale bytes do.
+#[test]
+fn compaction_threshold_counts_stale_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::StaleBytes(1024))
+        .open(temp_dir.path())?;
+
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert_eq!(store.stale_bytes(), 0);
+    assert_eq!(
+        log_files(temp_dir.path()),
+        vec![temp_dir.path().join("0.log")]
+    );
+
+    // overwriting builds up stale bytes until the threshold is reached
+    let mut iter = 0;
+    while store.stale_bytes() < 1024 {
+        store.set("key0".to_owned(), format!("{}", iter))?;
+        iter += 1;
+        assert!(iter < 1000, "No compaction triggered");
+    }
+    wait_for_compaction(temp_dir.path(), "0.log")?;
+    assert_eq!(store.get("key0".to_owned())?, Some(format!("{}", iter - 1)));
+    assert_eq!(store.get("key499".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compaction_ratio_trigger() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::StaleRatio {
+            ratio: 0.5,
+            min_stale: 0,
+        })
+        .open(temp_dir.path())?;
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    // 4 stale records out of 14
+    for key_id in 0..4 {
+        store.set(format!("key{}", key_id), "other".to_owned())?;
+    }
+    assert!(store.stale_bytes() > 0);
+    assert_eq!(log_files(temp_dir.path()).len(), 1);
+    assert_eq!(log_files(temp_dir.path())[0], temp_dir.path().join("0.log"));
+
+    // 10 stale records out of 20 reaches the ratio
+    for key_id in 4..10 {
+        store.set(format!("key{}", key_id), "other".to_owned())?;
+    }
+    wait_for_compaction(temp_dir.path(), "0.log")?;
+    assert_eq!(store.stale_bytes(), 0);
+    assert_eq!(log_files(temp_dir.path())[0], temp_dir.path().join("1.log"));
+    Ok(())
+}
+
+// Stale bytes are rebuilt when replaying the log on open.
+#[test]
+fn stale_bytes_survive_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stale = store.stale_bytes();
+    assert!(stale > 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stale_bytes(), stale);
+    Ok(())
+}
+
+// With a manual policy nothing is compacted until `compact_now` is called.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(log_files(temp_dir.path()).len(), 1);
+    assert!(store.stale_bytes() > 0);
+
+    store.compact_now()?;
+    assert_eq!(store.stale_bytes(), 0);
+    assert_eq!(
+        log_files(temp_dir.path()),
+        vec![temp_dir.path().join("1.log"), temp_dir.path().join("2.log")]
+    );
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("99".to_owned()));
+
+    // writes after compaction land in the new active log
+    store.set("key0".to_owned(), "new".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    Ok(())
+}
+
+// Writes made while a compaction runs must win over the compacted copies.
+#[test]
+fn writes_during_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::StaleBytes(4 * 1024))
+        .open(temp_dir.path())?;
+
+    for iter in 0..200 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        store.remove(format!("key{}", iter % 50))?;
+        for key_id in 0..50 {
+            let expected = if key_id == iter % 50 {
+                None
+            } else {
+                Some(format!("{}", iter))
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(log_files(temp_dir.path()).len() <= 3);
+    assert_eq!(store.get("key49".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("199".to_owned()));
+    Ok(())
+}
+
+// Output of a compaction that never finished is discarded on open, the old
+// generation is still complete.
+#[test]
+fn unfinished_compaction_cleaned_up_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let tmp = temp_dir.path().join("1.compact");
+    fs::write(&tmp, b"half written generation")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!tmp.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A crash between renaming the new generation and deleting the old one leaves
+// both, replaying them must still give the latest values.
+#[test]
+fn both_generations_replay_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let old_generation = fs::read(temp_dir.path().join("0.log"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact_now()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(!temp_dir.path().join("0.log").exists());
+    fs::write(temp_dir.path().join("0.log"), old_generation)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Clones of a store can be shared across threads, every thread sees the writes
+// of the others.
+#[test]
+fn concurrent_clones_share_the_store() -> Result<()> {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<KvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::StaleBytes(4096))
+        .open(temp_dir.path())?;
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..200 {
+                    let key = format!("key{}-{}", t, i % 20);
+                    store.set(key.clone(), format!("value{}", i))?;
+                    assert!(store.get(key)?.is_some());
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    for t in 0..8 {
+        for i in 180..200 {
+            let key = format!("key{}-{}", t, i % 20);
+            assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key7-19".to_owned())?,
+        Some("value199".to_owned())
+    );
+    Ok(())
+}
+
+// Reads never block on each other or on compaction deleting the logs they
+// point into.
+#[test]
+fn reads_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..2000 {
+                    let key_id = i % 100;
+                    let value = store.get(format!("key{}", key_id))?;
+                    assert_eq!(value, Some(format!("value{}", key_id)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for _ in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.compact_now()?;
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// With `mmap` set sealed logs are read through a mapping, values must be the
+// same whichever path serves them.
+#[test]
+fn mmap_reads_sealed_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        OpenOptions::new()
+            .compaction_policy(CompactionPolicy::Manual)
+            .mmap(true)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact_now()?;
+    // served from the active log
+    store.set("key0".to_owned(), "other".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("other".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // every log but the active one is mapped after reopening
+    let store = open()?;
+    store.set("key2".to_owned(), "other".to_owned())?;
+    for key_id in 3..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("other".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("other".to_owned()));
+    Ok(())
+}
+
+// Compaction writes a hint next to the new log and `open` rebuilds the index
+// from it without reading the records.
+#[test]
+fn hint_used_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact_now()?;
+    drop(store);
+    assert!(temp_dir.path().join("1.hint").exists());
+
+    // replaying would fail on this record, so only a hint gets the store open
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&log, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stale_bytes(), 0);
+    let mut corrupt = 0;
+    for key_id in 0..10 {
+        match store.get(format!("key{}", key_id)) {
+            Ok(value) => assert_eq!(value, Some(format!("value{}", key_id))),
+            Err(KvsError::Store(ErrorKind::Corruption { file: 1, .. })) => corrupt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+    assert_eq!(corrupt, 1);
+    Ok(())
+}
+
+// A hint that does not match its log is ignored and the log replayed.
+#[test]
+fn invalid_hint_falls_back_to_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact_now()?;
+    drop(store);
+
+    let hint = temp_dir.path().join("1.hint");
+    let mut bytes = fs::read(&hint)?;
+    bytes[20] ^= 0xff;
+    fs::write(&hint, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    // the hint is dropped along with its log by the next compaction
+    store.compact_now()?;
+    assert!(!hint.exists());
+    assert!(temp_dir.path().join("3.hint").exists());
+    Ok(())
+}
+
+// Keys and values are arbitrary bytes, the `String` API refuses values that are
+// not UTF-8.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0x00, 0x9f, 0x92, 0x96, 0xff];
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set_bytes(b"text".to_vec(), vec![0xc3, 0x28])?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    match store.get("text".to_owned()) {
+        Err(KvsError::Utf8(_)) => {}
+        other => panic!("expected a UTF-8 error, got {:?}", other),
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value));
+    store.remove_bytes(&key)?;
+    assert_eq!(store.get_bytes(&key)?, None);
+    Ok(())
+}
+
+// `--hex` and `--base64` decode keys and values and encode printed values.
+#[test]
+fn cli_hex_and_base64() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "--hex", "ff00", "00ff"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "--hex", "ff00"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("00ff").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "--base64", "/wA="])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("AP8=").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "--base64", "/wA="])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "--hex", "not-hex"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "--hex", "--base64", "ff"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// A batch is applied in order, survives reopening and compaction.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key1".to_owned())
+        .set("key2".to_owned(), "other".to_owned())
+        .set("key2".to_owned(), "value4".to_owned())
+        .remove("missing".to_owned());
+    assert_eq!(batch.len(), 5);
+    store.write(batch)?;
+    store.write(WriteBatch::new())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    let stale = store.stale_bytes();
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    assert_eq!(store.stale_bytes(), stale);
+    store.compact_now()?;
+    assert_eq!(store.stale_bytes(), 0);
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    Ok(())
+}
+
+// A batch cut short by a crash is dropped as a whole.
+#[test]
+fn torn_write_batch_is_not_applied() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value2".to_owned())
+        .set("key2".to_owned(), "value2".to_owned());
+    store.write(batch)?;
+    drop(store);
+
+    // lose the end of the second record of the batch
+    let log = log_files(temp_dir.path()).pop().unwrap();
+    let len = fs::metadata(&log)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A transaction sees its own writes and applies them on commit.
+#[test]
+fn transaction_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("key1".to_owned())?, Some("value1".to_owned()));
+    txn.set("key2".to_owned(), "value2".to_owned());
+    txn.remove("key1".to_owned())?;
+    assert_eq!(txn.get("key1".to_owned())?, None);
+    assert_eq!(txn.get("key2".to_owned())?, Some("value2".to_owned()));
+    match txn.remove("key1".to_owned()) {
+        Err(KvsError::Store(ErrorKind::NotFound)) => {}
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+    // nothing is visible before the commit
+    assert_eq!(store.get("key2".to_owned())?, None);
+    txn.commit()?;
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Writes to keys a transaction read make it fail, writes to other keys do not.
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut txn = store.transaction();
+    txn.get("key1".to_owned())?;
+    txn.set("key2".to_owned(), "value2".to_owned());
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    match txn.commit() {
+        Err(KvsError::Store(ErrorKind::TransactionConflict)) => {}
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let mut txn = store.transaction();
+    txn.get("key1".to_owned())?;
+    txn.set("key2".to_owned(), "value2".to_owned());
+    store.set("other".to_owned(), "value".to_owned())?;
+    txn.commit()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // a key that did not exist when read and was created since
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("key3".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    txn.set("key3".to_owned(), "mine".to_owned());
+    assert!(txn.commit().is_err());
+
+    // a key that was removed since it was read
+    let mut txn = store.transaction();
+    txn.get("key3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    txn.set("key4".to_owned(), "value4".to_owned());
+    assert!(txn.commit().is_err());
+    Ok(())
+}
+
+// Concurrent read-modify-write transactions retried on conflict never lose an
+// update.
+#[test]
+fn transaction_counter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let mut txn = store.transaction();
+                        let n = txn.get("counter".to_owned())?.unwrap_or_default();
+                        let n = n.parse::<u64>().unwrap_or(0) + 1;
+                        txn.set("counter".to_owned(), n.to_string());
+                        match txn.commit() {
+                            Ok(()) => break,
+                            Err(KvsError::Store(ErrorKind::TransactionConflict)) => continue,
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+// Conditional writes only happen when the current value is the expected one.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent(b"key1".to_vec(), b"value1".to_vec())?);
+    assert!(!store.set_if_absent(b"key1".to_vec(), b"value2".to_vec())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(!store.compare_and_swap(b"key1", Some(b"other"), Some(b"value2".to_vec()))?);
+    assert!(store.compare_and_swap(b"key1", Some(b"value1"), Some(b"value2".to_vec()))?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    assert!(!store.remove_if_equals(b"key1", b"value1")?);
+    assert!(store.remove_if_equals(b"key1", b"value2")?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!store.remove_if_equals(b"key1", b"value2")?);
+
+    // missing and expected to stay missing
+    assert!(store.compare_and_swap(b"key1", None, None)?);
+    assert!(store.compare_and_swap(b"key1", None, Some(b"value3".to_vec()))?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// `set --if-absent`, `rm --if-equals` and `cas` exit with a non-zero code when
+// their condition does not hold.
+#[test]
+fn cli_conditional_writes() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    kvs(&["set", "key1", "value1", "--if-absent"]).success();
+    kvs(&["set", "key1", "value2", "--if-absent"])
+        .failure()
+        .stdout(eq("Condition not met").trim());
+    kvs(&["cas", "key1", "--expected", "value2", "--new", "value3"]).failure();
+    kvs(&["cas", "key1", "--expected", "value1", "--new", "value3"]).success();
+    kvs(&["get", "key1"]).success().stdout(eq("value3").trim());
+    kvs(&["rm", "key1", "--if-equals", "value1"]).failure();
+    kvs(&["rm", "key1", "--if-equals", "value3"]).success();
+    kvs(&["cas", "key1", "--new", "value4"]).success();
+    kvs(&["cas", "key1", "--expected", "value4"]).success();
+    kvs(&["get", "key1"])
+        .success()
+        .stdout(eq("Key not found").trim());
+}
+
+// Keys set with a TTL disappear once it runs out, even across a reopen, and
+// compaction drops them.
+#[test]
+fn keys_expire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        b"key1".to_vec(),
+        b"value1".to_vec(),
+        std::time::Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        b"key2".to_vec(),
+        b"value2".to_vec(),
+        std::time::Duration::from_secs(3600),
+    )?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let ttl = store.ttl(b"key2")?.unwrap();
+    assert!(
+        ttl > std::time::Duration::from_secs(3500) && ttl <= std::time::Duration::from_secs(3600)
+    );
+    assert_eq!(store.ttl(b"key3")?, None);
+    // a TTL too long for the clock never runs out
+    store.set_with_ttl(
+        b"key4".to_vec(),
+        b"value4".to_vec(),
+        std::time::Duration::MAX,
+    )?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::Store(ErrorKind::NotFound))
+    ));
+    assert!(matches!(
+        store.ttl(b"key1"),
+        Err(KvsError::Store(ErrorKind::NotFound))
+    ));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.compact_now()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.ttl(b"key2")?.is_some());
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Keys that only expire build up stale bytes and trigger compaction.
+#[test]
+fn expired_keys_trigger_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::StaleBytes(1024))
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("session{}", key_id).into_bytes(),
+            b"token".to_vec(),
+            std::time::Duration::from_millis(100),
+        )?;
+    }
+    store.set("user".to_owned(), "alice".to_owned())?;
+    assert_eq!(store.stale_bytes(), 0);
+    assert_eq!(
+        log_files(temp_dir.path()),
+        vec![temp_dir.path().join("0.log")]
+    );
+
+    std::thread::sleep(std::time::Duration::from_millis(150));
+    store.set("user".to_owned(), "bob".to_owned())?;
+    wait_for_compaction(temp_dir.path(), "0.log")?;
+    assert_eq!(store.get("session0".to_owned())?, None);
+    assert_eq!(store.get("user".to_owned())?, Some("bob".to_owned()));
+    drop(store);
+
+    // overwriting an expired key does not count it twice
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stale_bytes(), 0);
+    store.set_with_ttl(
+        b"key".to_vec(),
+        b"v".to_vec(),
+        std::time::Duration::from_millis(1),
+    )?;
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    store.set("other".to_owned(), "v".to_owned())?;
+    let stale = store.stale_bytes();
+    assert!(stale > 0);
+    store.set("key".to_owned(), "v".to_owned())?;
+    assert_eq!(store.stale_bytes(), stale);
+    Ok(())
+}
+
+// `set --ttl` and `ttl` work from the command line.
+#[test]
+fn cli_ttl() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    kvs(&["set", "key1", "value1", "--ttl", "1h"]).success();
+    kvs(&["ttl", "key1"]).success().stdout(eq("3600s").trim());
+    kvs(&["set", "key2", "value2"]).success();
+    kvs(&["ttl", "key2"])
+        .success()
+        .stdout(eq("No expiry").trim());
+    kvs(&["ttl", "key3"])
+        .success()
+        .stdout(eq("Key not found").trim());
+    kvs(&["set", "key3", "value3", "--ttl", "1w"]).failure();
+    kvs(&["set", "key3", "value3", "--ttl", "99999999999999999d"])
+        .code(1)
+        .stderr(contains("too long"));
+    kvs(&["set", "key3", "value3", "--ttl", "18446744073709551s"]).success();
+    kvs(&["get", "key3"]).success().stdout(eq("value3").trim());
+    kvs(&["set", "key3", "value3", "--ttl", "100ms"]).success();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    kvs(&["get", "key3"])
+        .success()
+        .stdout(eq("Key not found").trim());
+}
+
+// Scans return live keys in order, within the range or prefix asked for.
+#[test]
+fn scan_ranges_and_prefixes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["b", "a2", "c", "a1", "a3", "ab"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("a3".to_owned())?;
+    store.set_bytes(vec![0xff, 0xff], b"max".to_vec())?;
+    store.set_bytes(vec![0xff, 0xff, 0x00], b"after-max".to_vec())?;
+    let keys = |scan: kvs::Scan| -> Result<Vec<Vec<u8>>> {
+        scan.map(|pair| pair.map(|(key, _)| key)).collect()
+    };
+
+    assert_eq!(
+        keys(store.scan(..))?,
+        vec![
+            b"a1".to_vec(),
+            b"a2".to_vec(),
+            b"ab".to_vec(),
+            b"b".to_vec(),
+            b"c".to_vec(),
+            vec![0xff, 0xff],
+            vec![0xff, 0xff, 0x00],
+        ]
+    );
+    assert_eq!(
+        keys(store.scan(b"a2".to_vec()..b"c".to_vec()))?,
+        vec![b"a2".to_vec(), b"ab".to_vec(), b"b".to_vec()]
+    );
+    assert_eq!(
+        keys(store.scan_prefix(b"a"))?,
+        vec![b"a1".to_vec(), b"a2".to_vec(), b"ab".to_vec()]
+    );
+    assert_eq!(
+        keys(store.scan_prefix(&[0xff, 0xff]))?,
+        vec![vec![0xff, 0xff], vec![0xff, 0xff, 0x00]]
+    );
+    assert!(keys(store.scan_prefix(b"d"))?.is_empty());
+
+    // values are read as the scan advances, even across a compaction
+    let mut scan = store.scan_prefix(b"a");
+    store.compact_now()?;
+    assert_eq!(
+        scan.next().unwrap()?,
+        (b"a1".to_vec(), b"value-a1".to_vec())
+    );
+    Ok(())
+}
+
+// `kvs scan` prints tab separated keys and values.
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    kvs(&["set", "user:2", "bob"]).success();
+    kvs(&["set", "user:1", "alice"]).success();
+    kvs(&["set", "group:1", "admins"]).success();
+    kvs(&["scan", "--prefix", "user:"])
+        .success()
+        .stdout(eq("user:1\talice\nuser:2\tbob\n"));
+    kvs(&["scan"])
+        .success()
+        .stdout(eq("group:1\tadmins\nuser:1\talice\nuser:2\tbob\n"));
+    kvs(&["scan", "--hex", "--prefix", "67"])
+        .success()
+        .stdout(eq("67726f75703a31\t61646d696e73\n"));
+}
+
+// A snapshot keeps seeing the store as it was, compaction keeps the logs it
+// reads from until it is dropped.
+#[test]
+fn snapshot_survives_writes_and_compaction() -> Result<()> {
+    for &mmap in &[false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = OpenOptions::new()
+            .compaction_policy(CompactionPolicy::Manual)
+            .mmap(mmap)
+            .open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        let snapshot = store.snapshot();
+        store.set("key1".to_owned(), "new1".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(snapshot.get("key3".to_owned())?, None);
+
+        store.compact_now()?;
+        assert_eq!(
+            log_files(temp_dir.path()),
+            vec![temp_dir.path().join("1.log"), temp_dir.path().join("2.log")]
+        );
+        assert!(temp_dir.path().join("0.retired").exists());
+        let pairs = snapshot.scan(..).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            pairs,
+            vec![
+                (b"key1".to_vec(), b"value1".to_vec()),
+                (b"key2".to_vec(), b"value2".to_vec()),
+            ]
+        );
+        assert_eq!(store.get("key1".to_owned())?, Some("new1".to_owned()));
+
+        drop(snapshot);
+        assert!(!temp_dir.path().join("0.retired").exists());
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// Logs left behind for a snapshot are removed on open instead of replayed.
+#[test]
+fn retired_logs_removed_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::copy(
+        temp_dir.path().join("0.log"),
+        temp_dir.path().join("0.retired"),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("0.retired").exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Earlier versions of a key can be read by sequence number, across a reopen,
+// until compaction drops them.
+#[test]
+fn get_at_earlier_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    assert_eq!(store.seq(), 0);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = store.seq();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .remove("key1".to_owned())
+        .set("key2".to_owned(), "value3".to_owned());
+    store.write(batch)?;
+    let removed = store.seq();
+    assert_eq!(removed, first + 2);
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    let last = store.seq();
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get_at(b"key1", 0)?, None);
+        assert_eq!(store.get_at(b"key1", first)?, Some(b"value1".to_vec()));
+        assert_eq!(store.get_at(b"key1", first + 1)?, Some(b"value2".to_vec()));
+        assert_eq!(store.get_at(b"key1", removed)?, None);
+        assert_eq!(store.get_at(b"key2", removed)?, Some(b"value3".to_vec()));
+        assert_eq!(store.get_at(b"key2", first + 1)?, None);
+        assert_eq!(store.get_at(b"key1", last)?, Some(b"value4".to_vec()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.seq(), last);
+    check(&store)?;
+
+    store.compact_now()?;
+    store.set("key2".to_owned(), "value5".to_owned())?;
+    assert!(matches!(
+        store.get_at(b"key1", first),
+        Err(KvsError::Store(ErrorKind::VersionCompacted))
+    ));
+    assert_eq!(store.get_at(b"key1", last)?, Some(b"value4".to_vec()));
+    assert_eq!(store.get_at(b"key2", last)?, Some(b"value3".to_vec()));
+    assert_eq!(store.get_at(b"key2", last + 1)?, Some(b"value5".to_vec()));
+    drop(store);
+
+    // the hint keeps track of what compaction dropped
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.seq(), last + 1);
+    assert!(matches!(
+        store.get_at(b"key1", first),
+        Err(KvsError::Store(ErrorKind::VersionCompacted))
+    ));
+    assert_eq!(store.get_at(b"key2", last)?, Some(b"value3".to_vec()));
+    Ok(())
+}
+
+// The sequence number never goes back after a reopen, even when the last
+// writes compaction saw were a removal and an expired key.
+#[test]
+fn seq_survives_compaction_of_removals() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.remove("b".to_owned())?;
+    store.set_with_ttl(
+        b"c".to_vec(),
+        b"3".to_vec(),
+        std::time::Duration::from_millis(1),
+    )?;
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    let last = store.seq();
+    assert_eq!(last, 4);
+    store.compact_now()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.seq(), last);
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("d".to_owned())?, None);
+    txn.set("d".to_owned(), "4".to_owned());
+    txn.commit()?;
+    assert_eq!(store.seq(), last + 1);
+    assert_eq!(store.get_at(b"d", last)?, None);
+    assert_eq!(store.get_at(b"d", last + 1)?, Some(b"4".to_vec()));
+    assert_eq!(store.get_at(b"a", last)?, Some(b"1".to_vec()));
+    Ok(())
+}
+
+// Logs written before records carried a sequence number are numbered in log
+// order when replayed.
+#[test]
+fn records_without_seq_are_replayed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy_set = |key: &[u8], value: &[u8]| {
+        let mut body = vec![1u8];
+        body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        body.extend_from_slice(key);
+        body.extend_from_slice(value);
+        let mut record = (body.len() as u32).to_le_bytes().to_vec();
+        record.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        record.extend_from_slice(&body);
+        record
+    };
+    let mut log = legacy_set(b"key1", b"value1");
+    log.extend_from_slice(&legacy_set(b"key1", b"value2"));
+    fs::write(temp_dir.path().join("0.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.seq(), 2);
+    assert_eq!(store.get_at(b"key1", 1)?, Some(b"value1".to_vec()));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.seq(), 3);
+    assert_eq!(store.get_at(b"key1", 2)?, Some(b"value2".to_vec()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Column families are separate key spaces with their own logs and compaction.
+#[test]
+fn column_families() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    let users = store.column_family("users")?;
+    let sessions = store.column_family("sessions")?;
+    store.set("key1".to_owned(), "default".to_owned())?;
+    users.set("key1".to_owned(), "alice".to_owned())?;
+    users.set("key1".to_owned(), "bob".to_owned())?;
+    sessions.set("key2".to_owned(), "token".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key1".to_owned())?, Some("bob".to_owned()));
+    assert_eq!(sessions.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    // every handle to a column family shares the same state
+    assert_eq!(
+        store.column_family("users")?.get("key1".to_owned())?,
+        Some("bob".to_owned())
+    );
+    assert_eq!(store.column_families()?, vec!["sessions", "users"]);
+
+    // compacting one column family leaves the others alone
+    let users_dir = temp_dir.path().join("cf").join("users");
+    users.compact_now()?;
+    assert_eq!(
+        log_files(&users_dir),
+        vec![users_dir.join("1.log"), users_dir.join("2.log")]
+    );
+    assert_eq!(
+        log_files(temp_dir.path()),
+        vec![temp_dir.path().join("0.log")]
+    );
+
+    for name in &["", "a/b", "..", "a b"] {
+        assert!(matches!(
+            store.column_family(name),
+            Err(KvsError::Store(ErrorKind::InvalidColumnFamily))
+        ));
+    }
+    drop((store, users, sessions));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.column_family("users")?.get("key1".to_owned())?,
+        Some("bob".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    Ok(())
+}
+
+// `--cf` points any command at a column family.
+#[test]
+fn cli_column_family() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    kvs(&["set", "key1", "value1", "--cf", "flags"]).success();
+    kvs(&["set", "key1", "value2"]).success();
+    kvs(&["--cf", "flags", "get", "key1"])
+        .success()
+        .stdout(eq("value1").trim());
+    kvs(&["get", "key1"]).success().stdout(eq("value2").trim());
+    kvs(&["rm", "key1", "--cf", "flags"]).success();
+    kvs(&["get", "key1", "--cf", "flags"])
+        .success()
+        .stdout(eq("Key not found").trim());
+    kvs(&["get", "key1", "--cf", "a/b"]).failure();
+}
+
+// A store can only be opened once at a time, by this process or another one.
+#[test]
+fn store_directory_is_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Store(ErrorKind::StoreLocked))
+    ));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("StoreLocked"));
+
+    // the lock goes away with the last handle
+    let snapshot = store.snapshot();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(snapshot);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A read-only store never touches its directory and can be opened next to a
+// writer.
+#[test]
+fn read_only_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+    // a directory without logs is an empty store
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(reader.scan(..).count(), 0);
+    drop(reader);
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 0);
+
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact_now()?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    // a torn write at the tail is skipped but left alone
+    let active = log_files(temp_dir.path()).pop().unwrap();
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&active)?
+        .write_all(&[1, 2, 3])?;
+    let active_len = fs::metadata(&active)?.len();
+    let files = |dir: &Path| -> Vec<PathBuf> {
+        let mut files = WalkDir::new(dir)
+            .into_iter()
+            .map(|e| e.unwrap().into_path())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+    let before = files(temp_dir.path());
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, Some("value3".to_owned()));
+    let read_only = |res: Result<()>| matches!(res, Err(KvsError::Store(ErrorKind::ReadOnly)));
+    assert!(read_only(
+        reader.set("key1".to_owned(), "value4".to_owned())
+    ));
+    assert!(read_only(reader.remove("key1".to_owned())));
+    assert!(read_only(reader.write(WriteBatch::new())));
+    assert!(read_only(reader.compact_now()));
+    assert!(read_only(
+        reader
+            .set_if_absent(b"key3".to_vec(), b"value5".to_vec())
+            .map(|_| ())
+    ));
+    // column families are not created either, one never written to is empty
+    let users = reader.column_family("users")?;
+    assert_eq!(users.get("key1".to_owned())?, None);
+    assert!(read_only(users.set("key1".to_owned(), "value4".to_owned())));
+    drop(users);
+    drop(reader);
+    assert_eq!(files(temp_dir.path()), before);
+    assert_eq!(fs::metadata(&active)?.len(), active_len);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--read-only", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--read-only", "set", "key1", "value4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("ReadOnly"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--read-only", "--cf", "nope", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    assert_eq!(files(temp_dir.path()), before);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Logs a compaction deletes while a read-only store opens should not make the
+// open fail or miss keys, their records are in the newer log.
+#[test]
+fn read_only_open_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let writer = {
+        let store = store.clone();
+        let stop = stop.clone();
+        std::thread::spawn(move || -> Result<()> {
+            while !stop.load(std::sync::atomic::Ordering::SeqCst) {
+                store.set("key0".to_owned(), "value0".to_owned())?;
+                store.compact_now()?;
+            }
+            Ok(())
+        })
+    };
+    for _ in 0..50 {
+        let reader = KvStore::open_read_only(temp_dir.path())?;
+        assert_eq!(reader.get("key99".to_owned())?, Some("value99".to_owned()));
+    }
+    stop.store(true, std::sync::atomic::Ordering::SeqCst);
+    writer.join().unwrap()?;
+    Ok(())
+}